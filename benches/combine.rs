@@ -0,0 +1,47 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use image::{DynamicImage, RgbaImage};
+use image_combiner::combiner::{CombineOptions, Registry};
+use image_combiner::floating_image::Precision;
+use image_combiner::pattern::Pattern;
+
+// Big enough that nothing fits in cache, so this measures what the combiners do with memory
+const WIDTH: u32 = 2048;
+const HEIGHT: u32 = 2048;
+
+/// An rgba image filled with a gradient, different for every seed so the combiners can't cheat
+fn synthetic(seed: u8) -> DynamicImage {
+    DynamicImage::ImageRgba8(RgbaImage::from_fn(WIDTH, HEIGHT, |x, y| {
+        image::Rgba([(x as u8).wrapping_add(seed), (y as u8).wrapping_mul(seed), seed, 200])
+    }))
+}
+
+fn combiners(c: &mut Criterion) {
+    let images = vec![synthetic(1), synthetic(2)];
+    // Every input gets read once and the output written once
+    let bytes = (images.len() as u64 + 1) * WIDTH as u64 * HEIGHT as u64 * 4;
+
+    let cases = [
+        ("alternate", "pixels", Pattern::Pixels),
+        ("alternate", "rows", Pattern::Rows(1)),
+        ("multiply", "", Pattern::default()),
+        ("over", "", Pattern::default())
+    ];
+
+    let mut group = c.benchmark_group("combine");
+    group.throughput(Throughput::Bytes(bytes));
+    group.sample_size(20);
+
+    for (mode, pattern_name, pattern) in cases {
+        let options = CombineOptions { pattern, ..CombineOptions::default() };
+        let registry = Registry::new(&options);
+        let combiner = registry.get(mode).unwrap();
+
+        let id = if pattern_name.is_empty() { BenchmarkId::from_parameter(mode) } else { BenchmarkId::new(mode, pattern_name) };
+        group.bench_function(id, |b| b.iter(|| combiner.combine(&images, Precision::U8).unwrap()));
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, combiners);
+criterion_main!(benches);
@@ -1,22 +1,695 @@
-/// image_1 & image_2 will be paths to each image file
+use crate::recipe::{self, Recipe};
+use clap::{Parser, Subcommand, ValueEnum};
+use image_combiner::combiner::{CombineOptions, Registry, DEFAULT_COMBINER};
+use image_combiner::output::{self, Background, EncoderOptions, TiffCompression};
+use image_combiner::pattern::Pattern;
+use image_combiner::resample::{Filter, Resampling};
+use image_combiner::size::{parse_color, Anchor, Fit, SizePolicy, Target};
+use image_combiner::tiled;
+use image_combiner::limits::DecodeLimits;
+use image_combiner::ImageDataErrors;
+use image::{ImageFormat, Rgba};
+use serde::{de, Deserialize, Deserializer};
+use std::str::FromStr;
+
+/// Combines two or more images into one by alternating, blending or compositing them
+#[derive(Debug, Parser)]
+#[command(name = "image-combiner", version, about, propagate_version = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+
+    /// How errors are printed, json is one line on stderr for scripts to read
+    #[arg(long, value_enum, global = true, default_value = "text")]
+    pub error_format: ErrorFormat,
+
+    /// Threads to work on, the output is the same however many there are [default: one per CPU core]
+    #[arg(short, long, global = true, value_parser = clap::value_parser!(u32).range(1..))]
+    pub jobs: Option<u32>,
+
+    /// Roughly how much memory combining can use e.g. 512M or 4G. Images too big for it get combined and saved
+    /// a strip at a time, which works for PNG and TIFF inputs saved as PNG or TIFF.
+    /// Batches share it between the images being combined at once
+    #[arg(long, global = true, value_name = "SIZE", value_parser = tiled::parse_memory_size)]
+    pub memory_budget: Option<u64>,
+
+    #[command(flatten)]
+    pub limits: LimitArgs
+}
+
+/// Limits on the images read, for when they come from somewhere that can't be trusted
+#[derive(Debug, clap::Args)]
+#[command(next_help_heading = "Decode limits")]
+pub struct LimitArgs {
+    /// Refuse images wider than this many pixels
+    #[arg(long, global = true, value_name = "PIXELS")]
+    pub max_width: Option<u32>,
+
+    /// Refuse images taller than this many pixels
+    #[arg(long, global = true, value_name = "PIXELS")]
+    pub max_height: Option<u32>,
+
+    /// Refuse images with more pixels than this in total, e.g. 100000000 for 100 megapixels
+    #[arg(long, global = true, value_name = "PIXELS")]
+    pub max_pixels: Option<u64>,
+
+    /// Most memory decoding a single image can use e.g. 512M or 4G, `none` for no limit
+    #[arg(long, global = true, value_name = "SIZE", default_value = "512M", value_parser = parse_max_alloc)]
+    pub max_alloc: MaxAlloc
+}
+
+/// An allocation limit, or none at all
+#[derive(Debug, Clone, Copy)]
+pub struct MaxAlloc(pub Option<u64>);
+
+fn parse_max_alloc(value: &str) -> Result<MaxAlloc, ImageDataErrors> {
+    match value {
+        "none" => Ok(MaxAlloc(None)),
+        _ => tiled::parse_memory_size(value).map(|bytes| MaxAlloc(Some(bytes)))
+    }
+}
+
+impl LimitArgs {
+    pub fn limits(&self) -> DecodeLimits {
+        DecodeLimits {
+            max_width: self.max_width,
+            max_height: self.max_height,
+            max_pixels: self.max_pixels,
+            max_alloc: self.max_alloc.0
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Combine two or more images into one
+    Combine(CombineArgs),
+    /// Combine many pairs of images, from two folders or a manifest, with the same settings
+    Batch(BatchArgs),
+    /// Compare two images, printing how different they are and optionally saving the difference
+    Diff(DiffArgs),
+    /// Lay images out side by side in a grid
+    Grid(GridArgs),
+    /// Cut an image up into a grid of tiles
+    Split(SplitArgs),
+    /// Print the format, size and colour type of images
+    Info(InfoArgs),
+    /// Print a shell completion script, e.g. `image-combiner completions bash > image-combiner.bash`
+    Completions {
+        shell: clap_complete::Shell
+    }
+}
+
+/// How errors get printed, `--error-format json` is for scripts that want to read them
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ErrorFormat {
+    Text,
+    Json
+}
+
+impl ErrorFormat {
+    /// Picks the error format out of the arguments on its own, so that it's known
+    /// even when the rest of the arguments can't be parsed
+    pub fn from_args() -> Self {
+        let args: Vec<String> = std::env::args().collect();
+        let json = args.iter().enumerate().any(|(index, arg)| {
+            arg == "--error-format=json" || (arg == "--error-format" && args.get(index + 1).map(String::as_str) == Some("json"))
+        });
+
+        if json { ErrorFormat::Json } else { ErrorFormat::Text }
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct CombineArgs {
+    /// Images to combine, the first is the bottom layer. Glob patterns like `renders/*.png` get expanded
+    /// and `-` reads one from standard input. Replaces the recipe's inputs when given
+    #[arg(required_unless_present = "recipe", value_name = "IMAGE")]
+    pub images: Vec<String>,
+
+    /// Recipe file to take the settings from, or the name of one saved in the recipes folder
+    /// of the config directory. Flags given as well override the recipe's values.
+    /// A recipe file's relative paths are relative to the folder it's in
+    #[arg(short, long)]
+    pub recipe: Option<String>,
+
+    /// Where to save the combined image, `-` writes it to standard output (which needs --format)
+    #[arg(short, long, required_unless_present = "recipe")]
+    pub output: Option<String>,
+
+    #[command(flatten)]
+    pub combining: CombiningArgs,
+
+    #[command(flatten)]
+    pub size: SizeArgs,
+
+    #[command(flatten)]
+    pub encoder: EncoderArgs
+}
+
+#[derive(Debug, clap::Args)]
+pub struct BatchArgs {
+    /// Folder with the first image of each pair, paired with the image of the same name in --right
+    #[arg(long, requires = "right", required_unless_present = "manifest", conflicts_with = "manifest")]
+    pub left: Option<String>,
+
+    /// Folder with the second image of each pair
+    #[arg(long, requires = "left")]
+    pub right: Option<String>,
+
+    /// CSV (left,right,name columns) or JSON (list of {left, right, name}) file listing the pairs instead.
+    /// Relative paths in it are relative to the folder it's in
+    #[arg(long)]
+    pub manifest: Option<String>,
+
+    /// Recipe to take the settings from, its inputs are left out as the pairs are used instead
+    #[arg(short, long)]
+    pub recipe: Option<String>,
+
+    /// Where to save each output, with {name}, {index}, {left} and {right} filled in for each pair e.g. `out/{name}.png`
+    #[arg(short, long, required_unless_present = "recipe")]
+    pub output: Option<String>,
+
+    #[command(flatten)]
+    pub combining: CombiningArgs,
+
+    #[command(flatten)]
+    pub size: SizeArgs,
+
+    #[command(flatten)]
+    pub encoder: EncoderArgs
+}
+
+/// Flags picking the combiner and its settings
+#[derive(Debug, clap::Args)]
+#[command(next_help_heading = "Combining")]
+pub struct CombiningArgs {
+    /// How the images are combined [default: alternate]
+    #[arg(short, long, value_parser = mode_names())]
+    pub mode: Option<String>,
+
+    /// How much each layer shows through the ones below it when blending or compositing, 0.0 - 1.0 [default: 1.0]
+    #[arg(long, value_parser = parse_opacity)]
+    pub opacity: Option<f32>,
+
+    /// Which image each pixel comes from when alternating: pixels, rows[:n], columns[:n], checkerboard[:n|WxH],
+    /// tiles:CxR, bitmap:PATH, random[:seed], bayer[:2|4|8|16] or blue-noise[:seed]. With three or more images
+    /// checkerboard runs in diagonal stripes, pixels follows reading order so its stripes depend on the width [default: checkerboard:1]
+    // Kept as text until the decode limits are known, a bitmap gets read with them
+    #[arg(long)]
+    pub pattern: Option<String>,
+
+    /// Share of the pixels each image gets with the noise patterns, one for each image e.g. `3,1`
+    #[arg(long, value_delimiter = ',')]
+    pub weights: Option<Vec<f32>>
+}
+
+#[derive(Debug, clap::Args)]
+pub struct DiffArgs {
+    pub first: String,
+    pub second: String,
+
+    /// Save an image of the difference here, black where the images are the same. `-` writes it to
+    /// standard output (which needs --format) and the stats go to stderr
+    #[arg(short, long)]
+    pub output: Option<String>,
+
+    /// How far apart a channel can be (0.0 - 1.0) before the pixel counts as different
+    #[arg(long, default_value_t = 0.0, value_parser = parse_opacity)]
+    pub threshold: f32,
+
+    #[command(flatten)]
+    pub size: SizeArgs,
+
+    #[command(flatten)]
+    pub encoder: EncoderArgs
+}
+
+#[derive(Debug, clap::Args)]
+pub struct GridArgs {
+    /// Images to lay out, left to right then top to bottom. Glob patterns get expanded, `-` is standard input
+    #[arg(required = true, value_name = "IMAGE")]
+    pub images: Vec<String>,
+
+    /// Where to save the grid, `-` writes it to standard output (which needs --format)
+    #[arg(short, long)]
+    pub output: String,
+
+    /// Images in each row [default: enough for the grid to be roughly square]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub columns: Option<u32>,
+
+    /// Space between the images in pixels
+    #[arg(long, default_value_t = 0)]
+    pub gap: u32,
+
+    /// Colour of the gaps and of any empty cells, `#rrggbb` or `#rrggbbaa`
+    #[arg(long, default_value = "#00000000", value_parser = parse_rgba)]
+    pub gap_color: Rgba<u8>,
+
+    #[command(flatten)]
+    pub size: SizeArgs,
+
+    #[command(flatten)]
+    pub encoder: EncoderArgs
+}
+
+#[derive(Debug, clap::Args)]
+pub struct SplitArgs {
+    pub image: String,
+
+    /// Where to save the tiles, `tiles.png` saves `tiles_0_0.png`, `tiles_0_1.png`... named by row then column
+    #[arg(short, long)]
+    pub output: String,
+
+    /// Tiles across
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+    pub columns: u32,
+
+    /// Tiles down
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+    pub rows: u32,
+
+    #[command(flatten)]
+    pub encoder: EncoderArgs
+}
+
+#[derive(Debug, clap::Args)]
+pub struct InfoArgs {
+    /// Images to describe, glob patterns get expanded and `-` is standard input
+    #[arg(required = true, value_name = "IMAGE")]
+    pub images: Vec<String>
+}
+
+/// Flags for how the images are made the same size.
+/// Also makes up the `[resize]` table of a recipe
+#[derive(Debug, Default, clap::Args, Deserialize)]
+#[command(next_help_heading = "Sizing")]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SizeArgs {
+    /// Size every image is brought to: smallest, largest or WIDTHxHEIGHT [default: smallest]
+    #[arg(long)]
+    #[serde(deserialize_with = "recipe::parse")]
+    pub size: Option<Target>,
+
+    /// How images of a different shape fit the size: stretch, crop, pad[:#rrggbb[aa]] or letterbox [default: crop]
+    #[arg(long)]
+    #[serde(deserialize_with = "recipe::parse")]
+    pub fit: Option<Fit>,
+
+    /// Part of the image kept when cropping, or where it sits when padding [default: center]
+    #[arg(long, value_parser = [
+        "top-left", "top", "top-right", "left", "center", "right", "bottom-left", "bottom", "bottom-right"
+    ])]
+    pub anchor: Option<String>,
+
+    /// Resampling filter, overrides the one picked by --quality [default: triangle]
+    #[arg(long, value_parser = ["nearest", "triangle", "catmull-rom", "gaussian", "lanczos3", "pixel-art"])]
+    pub filter: Option<String>,
+
+    /// Resampling preset, setting the filter and linear light together [default: balanced]
+    #[arg(long, value_parser = ["fast", "balanced", "best", "pixel-art"])]
+    pub quality: Option<String>,
+
+    /// Resize in linear light, so edges and fine detail don't get darker
+    #[arg(long)]
+    pub linear: bool
+}
+
+/// Flags for the output format and its encoder.
+/// Also makes up the `[encoder]` table of a recipe
+#[derive(Debug, Default, clap::Args, Deserialize)]
+#[command(next_help_heading = "Output")]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct EncoderArgs {
+    /// Format to save in, otherwise the output's extension decides e.g. png, jpg, webp, tiff
+    #[arg(long, value_parser = output::parse_format)]
+    #[serde(deserialize_with = "parse_format")]
+    pub format: Option<ImageFormat>,
+
+    /// JPEG quality, higher is better but bigger [default: 75]
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub jpeg_quality: Option<u8>,
+
+    /// Save progressive JPEGs, which show a rough version while loading
+    #[arg(long)]
+    pub progressive: bool,
+
+    /// PNG compression level [default: default]
+    #[arg(long, value_parser = ["fast", "default", "best"])]
+    pub png_compression: Option<String>,
+
+    /// PNG filter [default: adaptive]
+    #[arg(long, value_parser = ["none", "sub", "up", "avg", "paeth", "adaptive"])]
+    pub png_filter: Option<String>,
+
+    /// WebP quality from 0 - 100 for lossy, or lossless [default: lossless]
+    #[arg(long)]
+    #[serde(deserialize_with = "webp_quality")]
+    pub webp_quality: Option<WebpQuality>,
+
+    /// TIFF compression [default: none]
+    #[arg(long)]
+    #[serde(deserialize_with = "recipe::parse")]
+    pub tiff_compression: Option<TiffCompression>,
+
+    /// What see through pixels are laid on for formats without transparency, a colour or checkerboard[:size] [default: #ffffff]
+    #[arg(long)]
+    #[serde(deserialize_with = "recipe::parse")]
+    pub background: Option<Background>
+}
+
+/// Recipes can give the format by name, the same as --format
+fn parse_format<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<ImageFormat>, D::Error> {
+    let value: Option<String> = Option::deserialize(deserializer)?;
+    value.map(|value| output::parse_format(&value).map_err(de::Error::custom)).transpose()
+}
+
+/// Recipes can give the WebP quality as a number or as `"lossless"`
+fn webp_quality<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<WebpQuality>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Setting {
+        Quality(u8),
+        Text(String)
+    }
+
+    match Option::deserialize(deserializer)? {
+        Some(Setting::Quality(quality)) => Ok(Some(WebpQuality(Some(quality.min(100))))),
+        Some(Setting::Text(text)) => text.parse().map(Some).map_err(de::Error::custom),
+        None => Ok(None)
+    }
+}
+
+/// `lossless`, or a lossy quality from 0 - 100
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WebpQuality(pub Option<u8>);
+
+impl FromStr for WebpQuality {
+    type Err = ImageDataErrors;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "lossless" => Ok(WebpQuality(None)),
+            _ => match value.parse() {
+                Ok(quality) if quality <= 100 => Ok(WebpQuality(Some(quality))),
+                _ => Err(ImageDataErrors::InvalidEncoderOption(value.to_string()))
+            }
+        }
+    }
+}
+
+/// Names of the built in combiners, so clap can list them when an unknown one is given
+fn mode_names() -> Vec<&'static str> {
+    Registry::new(&CombineOptions::default()).names()
+}
+
+/// Parses a number from 0.0 - 1.0
+fn parse_opacity(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(opacity) if (0.0..=1.0).contains(&opacity) => Ok(opacity),
+        Ok(_) => Err("has to be from 0.0 to 1.0".to_string()),
+        Err(e) => Err(e.to_string())
+    }
+}
+
+fn parse_rgba(value: &str) -> Result<Rgba<u8>, String> {
+    parse_color(value).ok_or_else(|| "has to be a colour like #rrggbb or #rrggbbaa".to_string())
+}
+
+impl SizeArgs {
+    /// These flags, with anything not given taken from the recipe's
+    pub fn or(self, recipe: SizeArgs) -> SizeArgs {
+        SizeArgs {
+            size: self.size.or(recipe.size),
+            fit: self.fit.or(recipe.fit),
+            anchor: self.anchor.or(recipe.anchor),
+            filter: self.filter.or(recipe.filter),
+            quality: self.quality.or(recipe.quality),
+            // A switch can only be turned on, so either one turns it on
+            linear: self.linear || recipe.linear
+        }
+    }
+
+    /// The size policy these flags ask for, anything not given is left as the default
+    pub fn policy(&self) -> Result<SizePolicy, ImageDataErrors> {
+        let mut policy = SizePolicy::default();
+        if let Some(target) = self.size {
+            policy.target = target;
+        }
+        if let Some(fit) = self.fit {
+            policy.fit = fit;
+        }
+        if let Some(anchor) = &self.anchor {
+            policy.anchor = Anchor::from_str(anchor)?;
+        }
+        // Presets set both the filter and linear light, --filter and --linear then change them
+        if let Some(quality) = &self.quality {
+            policy.resampling = Resampling::preset(quality)?;
+        }
+        if let Some(filter) = &self.filter {
+            policy.resampling.filter = Filter::from_str(filter)?;
+        }
+        if self.linear {
+            policy.resampling.linear = true;
+        }
+        Ok(policy)
+    }
+}
+
+impl EncoderArgs {
+    /// These flags, with anything not given taken from the recipe's
+    pub fn or(self, recipe: EncoderArgs) -> EncoderArgs {
+        EncoderArgs {
+            format: self.format.or(recipe.format),
+            jpeg_quality: self.jpeg_quality.or(recipe.jpeg_quality),
+            progressive: self.progressive || recipe.progressive,
+            png_compression: self.png_compression.or(recipe.png_compression),
+            png_filter: self.png_filter.or(recipe.png_filter),
+            webp_quality: self.webp_quality.or(recipe.webp_quality),
+            tiff_compression: self.tiff_compression.or(recipe.tiff_compression),
+            background: self.background.or(recipe.background)
+        }
+    }
+
+    /// The encoder options these flags ask for, anything not given is left as the default
+    pub fn options(&self) -> Result<EncoderOptions, ImageDataErrors> {
+        let mut options = EncoderOptions::default();
+        options.format = self.format.or(options.format);
+        if let Some(quality) = self.jpeg_quality {
+            options.jpeg_quality = quality;
+        }
+        options.progressive |= self.progressive;
+        if let Some(compression) = &self.png_compression {
+            options.png_compression = output::parse_png_compression(compression)?;
+        }
+        if let Some(filter) = &self.png_filter {
+            options.png_filter = output::parse_png_filter(filter)?;
+        }
+        if let Some(WebpQuality(quality)) = self.webp_quality {
+            options.webp_quality = quality;
+        }
+        if let Some(compression) = self.tiff_compression {
+            options.tiff_compression = compression;
+        }
+        if let Some(background) = self.background {
+            options.background = background;
+        }
+        Ok(options)
+    }
+}
+
+/// images will be paths to each image file, output is where the result gets saved.
+/// mode is the name of the combiner to use,
+/// options are the settings for the combiners e.g. `--opacity 0.5` or `--pattern rows:4`,
+/// size is how the images are made the same size e.g. `--size 1920x1080 --fit pad:#ffffff --anchor top`,
+/// including how they get resampled e.g. `--filter lanczos3 --linear` or `--quality pixel-art`,
+/// encoder is the output format and its settings e.g. `--format jpg --jpeg-quality 90 --progressive`
 #[derive(Debug)]
 pub struct Args {
     // Fields need to be public to be accessible outside of module
-    pub image_1: String,
-    pub image_2: String,
-    pub output: String
+    pub images: Vec<String>,
+    pub output: String,
+    pub mode: String,
+    pub options: CombineOptions,
+    pub size: SizePolicy,
+    pub encoder: EncoderOptions
 }
 
-pub fn get_nth_arg(n: usize) -> String {
-    std::env::args().nth(n).unwrap()
+/// Expands a glob pattern (e.g. `renders/*.png`) into the paths it matches, sorted so the
+/// order the images get combined in is stable. Plain paths are passed through untouched
+pub fn expand_path(arg: String) -> Vec<String> {
+    // Only treat the argument as a pattern if it has glob characters in it,
+    // so a path that doesn't exist (yet) still reaches find_image_from_path and gets reported there
+    if !arg.contains(['*', '?', '[']) {
+        return vec![arg];
+    }
+
+    match glob::glob(&arg) {
+        Ok(paths) => {
+            let mut matches: Vec<String> = paths
+                .filter_map(Result::ok)
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect();
+            matches.sort();
+            matches
+        },
+        // Not a valid pattern, let the reader complain about it as a normal path
+        Err(_) => vec![arg]
+    }
+}
+
+/// Expands every path given
+pub fn expand_paths(args: Vec<String>) -> Vec<String> {
+    args.into_iter().flat_map(expand_path).collect()
 }
 
 impl Args {
-    pub fn new() -> Self {
-        Args {
-            image_1: get_nth_arg(1),
-            image_2: get_nth_arg(2),
-            output: get_nth_arg(3)
+    /// Settings for combining, from the combine subcommand's flags and the recipe if one was given.
+    /// Flags win over the recipe, anything neither sets is left as the default
+    pub fn new(combine: CombineArgs, limits: &DecodeLimits) -> Result<Self, ImageDataErrors> {
+        let mut recipe = load_recipe(&combine.recipe)?;
+
+        // Inputs on the command line replace the recipe's, rather than adding to them
+        let images = if combine.images.is_empty() { std::mem::take(&mut recipe.inputs) } else { combine.images };
+
+        Args::build(images, combine.output, recipe, combine.combining, combine.size, combine.encoder, limits)
+    }
+
+    /// Settings for a batch, the same as for combine but without any images,
+    /// and the output is the template each item's output is named with
+    pub fn batch(batch: BatchArgs, limits: &DecodeLimits) -> Result<Self, ImageDataErrors> {
+        let recipe = load_recipe(&batch.recipe)?;
+        Args::build(Vec::new(), batch.output, recipe, batch.combining, batch.size, batch.encoder, limits)
+    }
+
+    fn build(
+        images: Vec<String>,
+        output: Option<String>,
+        recipe: Recipe,
+        combining: CombiningArgs,
+        size: SizeArgs,
+        encoder: EncoderArgs,
+        limits: &DecodeLimits
+    ) -> Result<Self, ImageDataErrors> {
+        let mut options = CombineOptions::default();
+        if let Some(opacity) = combining.opacity.or(recipe.opacity) {
+            options.opacity = opacity.clamp(0.0, 1.0);
+        }
+        if let Some(pattern) = combining.pattern.or(recipe.pattern) {
+            options.pattern = Pattern::parse_with_limits(&pattern, limits)?;
+        }
+        options.weights = combining.weights.or(recipe.weights);
+
+        Ok(Args {
+            images: expand_paths(images),
+            output: output.or(recipe.output).ok_or(ImageDataErrors::MissingOutput)?,
+            mode: combining.mode.or(recipe.mode).unwrap_or_else(|| DEFAULT_COMBINER.to_string()),
+            options,
+            size: size.or(recipe.resize).policy()?,
+            encoder: encoder.or(recipe.encoder).options()?
+        })
+    }
+}
+
+/// The recipe asked for, or an empty one that leaves everything to the flags
+fn load_recipe(name: &Option<String>) -> Result<Recipe, ImageDataErrors> {
+    match name {
+        Some(name) => Recipe::load(name),
+        None => Ok(Recipe::default())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image_combiner::STDIO_PATH;
+
+    /// Settings for `combine` with the arguments given after it
+    fn combine_args(args: &[&str]) -> Result<Args, ImageDataErrors> {
+        let cli = Cli::try_parse_from(["image-combiner", "combine"].iter().chain(args)).unwrap();
+        match cli.command {
+            Command::Combine(combine) => Args::new(combine, &DecodeLimits::default()),
+            other => panic!("parsed as {:?}", other)
         }
     }
+
+    #[test]
+    fn globs_expand_to_sorted_paths() {
+        let dir = std::env::temp_dir().join(format!("image-combiner-{}-globs", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["c.png", "a.png", "b.png", "2.png", "10.png", "notes.txt"] {
+            std::fs::write(dir.join(name), []).unwrap();
+        }
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        let pngs = expand_path(path("*.png"));
+        let single = expand_path(path("?.png"));
+        let none = expand_path(path("*.jpg"));
+        let both = expand_paths(vec![path("[ab].png"), path("notes.txt"), path("1*")]);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // Sorted by name, not by number or when they were made
+        assert_eq!(pngs, ["10.png", "2.png", "a.png", "b.png", "c.png"].map(path));
+        assert_eq!(single, ["2.png", "a.png", "b.png", "c.png"].map(path));
+        assert!(none.is_empty());
+        // Each argument is expanded in place, in the order they were given
+        assert_eq!(both, ["a.png", "b.png", "notes.txt", "10.png"].map(path));
+    }
+
+    #[test]
+    fn plain_paths_are_left_alone() {
+        // Even ones that don't exist, so reading them reports the path as it was given
+        assert_eq!(expand_path("missing/image.png".to_string()), ["missing/image.png"]);
+        assert_eq!(expand_path(STDIO_PATH.to_string()), [STDIO_PATH]);
+        // Not a valid pattern either, it's left to be read as a path
+        assert_eq!(expand_path("broken[.png".to_string()), ["broken[.png"]);
+    }
+
+    #[test]
+    fn flags_override_the_recipe() {
+        let dir = std::env::temp_dir().join(format!("image-combiner-{}-args-recipe", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let recipe = dir.join("job.toml");
+        std::fs::write(
+            &recipe,
+            "inputs = [\"a.png\", \"b.png\"]\noutput = \"out.jpg\"\nmode = \"multiply\"\nopacity = 0.8\n\n\
+             [resize]\nsize = \"64x32\"\nfit = \"letterbox\"\n\n[encoder]\njpeg-quality = 90\n"
+        )
+        .unwrap();
+        let recipe = recipe.to_string_lossy().into_owned();
+
+        let from_recipe = combine_args(&["-r", &recipe]);
+        let overridden = combine_args(&["-r", &recipe, "c.png", "d.png", "-o", "mine.png", "--opacity", "0.25", "--size", "10x10", "--jpeg-quality", "50"]);
+        let _ = std::fs::remove_dir_all(&dir);
+        let (from_recipe, overridden) = (from_recipe.unwrap(), overridden.unwrap());
+
+        assert_eq!(from_recipe.images, [dir.join("a.png").to_string_lossy(), dir.join("b.png").to_string_lossy()]);
+        assert_eq!(from_recipe.output, dir.join("out.jpg").to_string_lossy());
+        assert_eq!(from_recipe.options.opacity, 0.8);
+        assert_eq!(from_recipe.size.target, Target::Fixed(64, 32));
+        assert_eq!(from_recipe.encoder.jpeg_quality, 90);
+
+        // Inputs and output given on the command line are relative to where it's run, and replace the recipe's
+        assert_eq!(overridden.images, ["c.png", "d.png"]);
+        assert_eq!(overridden.output, "mine.png");
+        assert_eq!(overridden.options.opacity, 0.25);
+        assert_eq!(overridden.size.target, Target::Fixed(10, 10));
+        assert_eq!(overridden.encoder.jpeg_quality, 50);
+        // Anything not given on the command line still comes from the recipe
+        assert_eq!(overridden.mode, "multiply");
+        assert_eq!(overridden.size.fit, Fit::Letterbox);
+    }
+
+    #[test]
+    fn switches_can_be_turned_on_by_either() {
+        let on = SizeArgs { linear: true, ..SizeArgs::default() };
+        assert!(SizeArgs::default().or(SizeArgs { linear: true, ..SizeArgs::default() }).linear);
+        assert!(on.or(SizeArgs::default()).linear);
+        assert!(!SizeArgs::default().or(SizeArgs::default()).linear);
+
+        let progressive = EncoderArgs { progressive: true, ..EncoderArgs::default() };
+        assert!(EncoderArgs::default().or(progressive).progressive);
+    }
 }
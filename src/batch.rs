@@ -0,0 +1,342 @@
+use crate::{Combine, ImageDataErrors, STDIO_PATH};
+use image::ImageFormat;
+use rayon::prelude::*;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Two images to combine together, and the name the output is made from
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Pair {
+    pub left: String,
+    pub right: String,
+    // Manifests can leave the name out, the left image's file name is used then
+    #[serde(default)]
+    pub name: String
+}
+
+/// Pairs up the images in two folders that have the same file name, ignoring the extension
+/// so `left/001.png` goes with `right/001.jpg`. Files that aren't images are skipped.
+/// Images that can't be paired are returned as failures so they can be reported, that's ones without
+/// a partner and ones sharing their name with another image in the same folder (`a.png` and `a.jpg`)
+pub fn pair_directories(left: &str, right: &str) -> Result<(Vec<Pair>, Vec<ImageDataErrors>), ImageDataErrors> {
+    let mut left_images = images_by_name(left)?;
+    let mut right_images = images_by_name(right)?;
+
+    let mut pairs = Vec::new();
+    let mut failures = Vec::new();
+    for (name, left) in std::mem::take(&mut left_images) {
+        match (left, right_images.remove(&name)) {
+            (left, Some(right)) if left.len() == 1 && right.len() == 1 => {
+                pairs.push(Pair { left: left[0].clone(), right: right[0].clone(), name })
+            },
+            (left, right) => {
+                let right = right.unwrap_or_default();
+                let ambiguous = left.len() > 1 || right.len() > 1;
+                failures.extend(unpaired(&name, left, ambiguous).chain(unpaired(&name, right, ambiguous)));
+            }
+        }
+    }
+    for (name, right) in right_images {
+        let ambiguous = right.len() > 1;
+        failures.extend(unpaired(&name, right, ambiguous));
+    }
+
+    Ok((pairs, failures))
+}
+
+/// Why each of the images with the name couldn't be paired, ambiguous is when either folder has more than one
+fn unpaired<'a>(name: &'a str, paths: Vec<String>, ambiguous: bool) -> impl Iterator<Item = ImageDataErrors> + 'a {
+    paths.into_iter().map(move |path| {
+        if ambiguous { ImageDataErrors::DuplicateName(path, name.to_string()) } else { ImageDataErrors::UnmatchedFile(path) }
+    })
+}
+
+/// Images in a folder keyed by their file name without the extension, sorted by name.
+/// More than one image can have the same name when only their extensions differ
+fn images_by_name(dir: &str) -> Result<BTreeMap<String, Vec<String>>, ImageDataErrors> {
+    let read_error = |e| ImageDataErrors::UnableToReadDirectory(dir.to_string(), e);
+    let mut images: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    for entry in std::fs::read_dir(dir).map_err(read_error)? {
+        let path = entry.map_err(read_error)?.path();
+        // Only files with an extension the image crate knows, so notes and hidden files get left alone
+        if !path.is_file() || ImageFormat::from_path(&path).is_err() {
+            continue;
+        }
+        if let Some(stem) = path.file_stem() {
+            images.entry(stem.to_string_lossy().into_owned()).or_default().push(path.to_string_lossy().into_owned());
+        }
+    }
+    // read_dir doesn't promise any order, this keeps the failures the same from run to run
+    for paths in images.values_mut() {
+        paths.sort();
+    }
+    Ok(images)
+}
+
+/// Reads the pairs from a manifest. A `.json` one is a list of `{"left": ..., "right": ..., "name": ...}` objects,
+/// anything else is read as CSV with a `left,right,name` header (name is optional in both).
+/// Relative paths in it are relative to the folder the manifest is in, so it works from anywhere
+pub fn read_manifest(path: &str) -> Result<Vec<Pair>, ImageDataErrors> {
+    let contents = std::fs::read_to_string(path).map_err(|e| ImageDataErrors::UnableToReadManifest(path.to_string(), e))?;
+    let invalid = |message: String| ImageDataErrors::InvalidManifest(path.to_string(), message);
+
+    let pairs: Vec<Pair> = if path.ends_with(".json") {
+        serde_json::from_str(&contents).map_err(|e| invalid(e.to_string()))?
+    } else {
+        read_csv(&contents).map_err(invalid)?
+    };
+
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    Ok(pairs
+        .into_iter()
+        .map(|mut pair| {
+            if pair.name.is_empty() {
+                pair.name = file_stem(&pair.left);
+            }
+            pair.left = relative_to(dir, pair.left);
+            pair.right = relative_to(dir, pair.right);
+            pair
+        })
+        .collect())
+}
+
+/// The path as seen from the folder given, absolute paths and `-` (standard input) are left alone
+fn relative_to(dir: &Path, path: String) -> String {
+    if path == STDIO_PATH || Path::new(&path).is_absolute() {
+        return path;
+    }
+    dir.join(path).to_string_lossy().into_owned()
+}
+
+/// Reads pairs from CSV, the columns are found by the header so they can be in any order.
+/// Rows can leave the name off even when the header has it
+fn read_csv(contents: &str) -> Result<Vec<Pair>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .comment(Some(b'#'))
+        .from_reader(contents.as_bytes());
+
+    let headers = reader.headers().map_err(|e| e.to_string())?.clone();
+    let column = |name: &str| headers.iter().position(|header| header == name);
+    let left = column("left").ok_or("there's no left column")?;
+    let right = column("right").ok_or("there's no right column")?;
+    let name = column("name");
+
+    let mut pairs = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| e.to_string())?;
+        let field = |index: usize| record.get(index).unwrap_or_default().to_string();
+        pairs.push(Pair {
+            left: field(left),
+            right: field(right),
+            name: name.map(field).unwrap_or_default()
+        });
+    }
+    Ok(pairs)
+}
+
+fn file_stem(path: &str) -> String {
+    Path::new(path).file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+/// Where each output of a batch is saved e.g. `out/{name}.png`. The placeholders are
+/// {name}, {index} (counting from 1), {left} and {right} (the file names of the inputs, without extensions)
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameTemplate(String);
+
+impl FromStr for NameTemplate {
+    type Err = ImageDataErrors;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || ImageDataErrors::InvalidTemplate(value.to_string());
+
+        // Every { has to start one of the placeholders, and there has to be one that changes between items
+        // or every output would be saved over the last
+        let mut rest = value;
+        let mut unique = false;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}').ok_or_else(invalid)? + start;
+            match &rest[start + 1..end] {
+                "name" | "index" => unique = true,
+                "left" | "right" => (),
+                _ => return Err(invalid())
+            }
+            rest = &rest[end + 1..];
+        }
+
+        if unique { Ok(NameTemplate(value.to_string())) } else { Err(invalid()) }
+    }
+}
+
+impl NameTemplate {
+    /// Output path for the pair at index (counting from 0)
+    pub fn render(&self, pair: &Pair, index: usize) -> String {
+        self.0
+            .replace("{name}", &pair.name)
+            .replace("{index}", &(index + 1).to_string())
+            .replace("{left}", &file_stem(&pair.left))
+            .replace("{right}", &file_stem(&pair.right))
+    }
+}
+
+/// What happened to one item of a batch
+#[derive(Debug)]
+pub struct Outcome {
+    pub name: String,
+    pub output: String,
+    pub result: Result<(), ImageDataErrors>
+}
+
+/// Combines every pair with the settings of the combine given, saving each to where the template says.
+/// An item failing doesn't stop the rest, every item's outcome is returned in order.
+/// Items that would be saved to the same place as another fail before anything starts, rather than saving over each other.
+/// Items run in parallel, on_outcome gets called as each one finishes (so not in order) e.g. to print progress
+pub fn run(pairs: &[Pair], combine: &Combine, template: &NameTemplate, on_outcome: impl Fn(&Outcome) + Sync) -> Vec<Outcome> {
+    let outputs: Vec<String> = pairs.iter().enumerate().map(|(index, pair)| template.render(pair, index)).collect();
+    let mut uses: HashMap<&str, usize> = HashMap::new();
+    for output in &outputs {
+        *uses.entry(output.as_str()).or_default() += 1;
+    }
+
+    let duplicated: Vec<Option<Outcome>> = pairs
+        .iter()
+        .zip(&outputs)
+        .map(|(pair, output)| {
+            (uses[output.as_str()] > 1).then(|| {
+                let result = Err(ImageDataErrors::DuplicateOutput(output.clone(), pair.name.clone()));
+                let outcome = Outcome { name: pair.name.clone(), output: output.clone(), result };
+                on_outcome(&outcome);
+                outcome
+            })
+        })
+        .collect();
+
+    pairs
+        .par_iter()
+        .zip(outputs)
+        .zip(duplicated)
+        .map(|((pair, output), duplicated)| {
+            if let Some(outcome) = duplicated {
+                return outcome;
+            }
+            let result = create_parent(&output).and_then(|_| {
+                combine.clone().input(pair.left.as_str()).input(pair.right.as_str()).save(output.as_str()).map(|_| ())
+            });
+
+            let outcome = Outcome { name: pair.name.clone(), output, result };
+            on_outcome(&outcome);
+            outcome
+        })
+        .collect()
+}
+
+/// Makes the folder the output goes in, so templates like `out/{name}.png` work on a fresh checkout
+fn create_parent(output: &str) -> Result<(), ImageDataErrors> {
+    match Path::new(output).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => std::fs::create_dir_all(parent)
+            .map_err(|e| ImageDataErrors::UnableToSaveImage(output.to_string(), image::ImageError::IoError(e))),
+        _ => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh folder in the temp directory with an empty file for each name
+    fn folder(name: &str, files: &[&str]) -> String {
+        let dir = std::env::temp_dir().join(format!("image-combiner-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for file in files {
+            std::fs::write(dir.join(file), []).unwrap();
+        }
+        dir.to_string_lossy().into_owned()
+    }
+
+    fn file_name(path: &str) -> &str {
+        Path::new(path).file_name().unwrap().to_str().unwrap()
+    }
+
+    /// Writes a manifest into a fresh folder, returning its path
+    fn manifest(name: &str, contents: &str) -> String {
+        let path = Path::new(&folder(&format!("manifest-{}", name), &[])).join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn manifest_paths_are_relative_to_the_manifest() {
+        let absolute = if cfg!(windows) { "C:\\images\\b.png" } else { "/images/b.png" };
+        let csv = manifest("pairs.csv", &format!("# comments are skipped\nright,left,name\nr/a.png,l/a.png,first\n{},l/b.png\n", absolute));
+        let json = manifest("pairs.json", &format!(r#"[{{"left": "l/a.png", "right": "r/a.png", "name": "first"}}, {{"left": "l/b.png", "right": {:?}}}]"#, absolute));
+
+        for path in [csv, json] {
+            let pairs = read_manifest(&path).unwrap();
+            let dir = Path::new(&path).parent().unwrap();
+            let _ = std::fs::remove_dir_all(dir);
+
+            let beside = |file: &str| dir.join(file).to_string_lossy().into_owned();
+            let expected = [
+                Pair { left: beside("l/a.png"), right: beside("r/a.png"), name: "first".to_string() },
+                // The name is optional, the left image's file name is used without it. Absolute paths stay as they are
+                Pair { left: beside("l/b.png"), right: absolute.to_string(), name: "b".to_string() }
+            ];
+            assert_eq!(pairs, expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn manifests_need_both_sides() {
+        for (name, contents) in [("left.csv", "left,name\na.png,a\n"), ("right.csv", "right\na.png\n"), ("right.json", r#"[{"left": "a.png"}]"#)] {
+            let path = manifest(name, contents);
+            let result = read_manifest(&path);
+            let _ = std::fs::remove_dir_all(Path::new(&path).parent().unwrap());
+            assert!(matches!(result, Err(ImageDataErrors::InvalidManifest(..))), "{}", name);
+        }
+        assert!(matches!(read_manifest("/no/such/manifest.csv"), Err(ImageDataErrors::UnableToReadManifest(..))));
+    }
+
+    #[test]
+    fn images_sharing_a_name_are_not_paired() {
+        let left = folder("batch-left", &["a.png", "a.jpg", "b.png", "c.png", "notes.txt"]);
+        let right = folder("batch-right", &["a.png", "b.jpg", "d.png"]);
+        let (pairs, failures) = pair_directories(&left, &right).unwrap();
+        let _ = (std::fs::remove_dir_all(&left), std::fs::remove_dir_all(&right));
+
+        let names: Vec<(&str, &str)> = pairs.iter().map(|pair| (file_name(&pair.left), file_name(&pair.right))).collect();
+        assert_eq!(names, [("b.png", "b.jpg")]);
+
+        let failures: Vec<(&str, bool)> = failures
+            .iter()
+            .map(|error| match error {
+                ImageDataErrors::DuplicateName(path, _) => (file_name(path), true),
+                ImageDataErrors::UnmatchedFile(path) => (file_name(path), false),
+                other => panic!("unexpected {}", other)
+            })
+            .collect();
+        // The right a could go with either of the left ones, so it's just as ambiguous
+        assert_eq!(failures, [("a.jpg", true), ("a.png", true), ("a.png", true), ("c.png", false), ("d.png", false)]);
+    }
+
+    #[test]
+    fn items_saved_to_the_same_place_fail_before_running() {
+        let pair = |name: &str| Pair { left: format!("{}-left.png", name), right: format!("{}-right.png", name), name: name.to_string() };
+        let pairs = [pair("a"), pair("b"), pair("a")];
+        let output = std::env::temp_dir().join(format!("image-combiner-{}-batch-{{name}}.png", std::process::id()));
+        let template: NameTemplate = output.to_string_lossy().parse().unwrap();
+
+        let outcomes = run(&pairs, &Combine::new(), &template, |_| ());
+        let duplicated: Vec<bool> = outcomes
+            .iter()
+            .map(|outcome| matches!(outcome.result, Err(ImageDataErrors::DuplicateOutput(..))))
+            .collect();
+        assert_eq!(duplicated, [true, false, true]);
+        // b isn't held up by the others, it only fails because its inputs don't exist
+        assert!(matches!(outcomes[1].result, Err(ImageDataErrors::UnableToReadImageFromPath(..))));
+    }
+}
@@ -0,0 +1,440 @@
+use crate::combiner::Combiner;
+use crate::floating_image::{DynamicFloatingImage, FloatingImage, Precision, Sample};
+use crate::ImageDataErrors;
+use image::DynamicImage;
+use rayon::prelude::*;
+
+/// Photoshop style blend modes. Most are worked out per colour channel (separable),
+/// hue, saturation, color and luminosity work on the whole colour at once (non-separable).
+/// Formulas follow the W3C Compositing and Blending spec, on values from 0.0 - 1.0
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlendMode {
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    SoftLight,
+    HardLight,
+    ColorDodge,
+    ColorBurn,
+    Darken,
+    Lighten,
+    Difference,
+    Exclusion,
+    Add,
+    Subtract,
+    Hue,
+    Saturation,
+    Color,
+    Luminosity
+}
+
+impl BlendMode {
+    /// Every blend mode, used to fill up the combiner registry
+    pub const ALL: [BlendMode; 18] = [
+        BlendMode::Normal,
+        BlendMode::Multiply,
+        BlendMode::Screen,
+        BlendMode::Overlay,
+        BlendMode::SoftLight,
+        BlendMode::HardLight,
+        BlendMode::ColorDodge,
+        BlendMode::ColorBurn,
+        BlendMode::Darken,
+        BlendMode::Lighten,
+        BlendMode::Difference,
+        BlendMode::Exclusion,
+        BlendMode::Add,
+        BlendMode::Subtract,
+        BlendMode::Hue,
+        BlendMode::Saturation,
+        BlendMode::Color,
+        BlendMode::Luminosity
+    ];
+
+    /// Name the mode is picked by on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            BlendMode::Normal => "normal",
+            BlendMode::Multiply => "multiply",
+            BlendMode::Screen => "screen",
+            BlendMode::Overlay => "overlay",
+            BlendMode::SoftLight => "soft-light",
+            BlendMode::HardLight => "hard-light",
+            BlendMode::ColorDodge => "color-dodge",
+            BlendMode::ColorBurn => "color-burn",
+            BlendMode::Darken => "darken",
+            BlendMode::Lighten => "lighten",
+            BlendMode::Difference => "difference",
+            BlendMode::Exclusion => "exclusion",
+            BlendMode::Add => "add",
+            BlendMode::Subtract => "subtract",
+            BlendMode::Hue => "hue",
+            BlendMode::Saturation => "saturation",
+            BlendMode::Color => "color",
+            BlendMode::Luminosity => "luminosity"
+        }
+    }
+
+    /// Blends the colour of the backdrop (the layers below, cb) with the source (the layer on top, cs)
+    pub fn blend(self, cb: [f32; 3], cs: [f32; 3]) -> [f32; 3] {
+        match self {
+            // Keeps the hue of the source, with the saturation and luminosity of the backdrop
+            BlendMode::Hue => set_lum(set_sat(cs, sat(cb)), lum(cb)),
+            // Keeps the saturation of the source, with the hue and luminosity of the backdrop
+            BlendMode::Saturation => set_lum(set_sat(cb, sat(cs)), lum(cb)),
+            // Keeps the hue and saturation of the source, with the luminosity of the backdrop
+            BlendMode::Color => set_lum(cs, lum(cb)),
+            // Keeps the luminosity of the source, with the hue and saturation of the backdrop
+            BlendMode::Luminosity => set_lum(cb, lum(cs)),
+            // Everything else is blended one channel at a time
+            _ => [0, 1, 2].map(|channel| self.blend_channel(cb[channel], cs[channel]))
+        }
+    }
+
+    /// Blends one channel of the backdrop with the source, for the separable modes
+    fn blend_channel(self, cb: f32, cs: f32) -> f32 {
+        match self {
+            BlendMode::Normal => cs,
+            BlendMode::Multiply => cb * cs,
+            BlendMode::Screen => cb + cs - cb * cs,
+            // Overlay is hard light with the layers swapped
+            BlendMode::Overlay => BlendMode::HardLight.blend_channel(cs, cb),
+            BlendMode::SoftLight => {
+                if cs <= 0.5 {
+                    cb - (1.0 - 2.0 * cs) * cb * (1.0 - cb)
+                } else {
+                    let d = if cb <= 0.25 {
+                        ((16.0 * cb - 12.0) * cb + 4.0) * cb
+                    } else {
+                        cb.sqrt()
+                    };
+                    cb + (2.0 * cs - 1.0) * (d - cb)
+                }
+            },
+            BlendMode::HardLight => {
+                if cs <= 0.5 {
+                    BlendMode::Multiply.blend_channel(cb, 2.0 * cs)
+                } else {
+                    BlendMode::Screen.blend_channel(cb, 2.0 * cs - 1.0)
+                }
+            },
+            BlendMode::ColorDodge => {
+                if cb == 0.0 {
+                    0.0
+                } else if cs >= 1.0 {
+                    1.0
+                } else {
+                    (cb / (1.0 - cs)).min(1.0)
+                }
+            },
+            BlendMode::ColorBurn => {
+                if cb >= 1.0 {
+                    1.0
+                } else if cs == 0.0 {
+                    0.0
+                } else {
+                    1.0 - ((1.0 - cb) / cs).min(1.0)
+                }
+            },
+            BlendMode::Darken => cb.min(cs),
+            BlendMode::Lighten => cb.max(cs),
+            BlendMode::Difference => (cb - cs).abs(),
+            BlendMode::Exclusion => cb + cs - 2.0 * cb * cs,
+            BlendMode::Add => (cb + cs).min(1.0),
+            BlendMode::Subtract => (cb - cs).max(0.0),
+            // Non-separable modes never get here, blend handles them on the whole colour
+            BlendMode::Hue | BlendMode::Saturation | BlendMode::Color | BlendMode::Luminosity => cs
+        }
+    }
+}
+
+/// Stacks the images on top of each other, the first image is the bottom layer
+/// and every image after it is blended onto the result so far
+pub struct Blend {
+    pub mode: BlendMode,
+    // How much each layer on top shows through, from 0.0 (not at all) to 1.0 (fully)
+    pub opacity: f32
+}
+
+impl Combiner for Blend {
+    fn name(&self) -> &'static str {
+        self.mode.name()
+    }
+
+    fn combine(&self, images: &[DynamicImage], precision: Precision) -> Result<DynamicFloatingImage, ImageDataErrors> {
+        Ok(match precision {
+            Precision::U8 => blend::<u8>(images, self.mode, self.opacity)?.into_dynamic(),
+            Precision::U16 => blend::<u16>(images, self.mode, self.opacity)?.into_dynamic(),
+            Precision::F32 => blend::<f32>(images, self.mode, self.opacity)?.into_dynamic()
+        })
+    }
+}
+
+/// Blends the images with samples of type T
+fn blend<T: Sample>(images: &[DynamicImage], mode: BlendMode, opacity: f32) -> Result<FloatingImage<T>, ImageDataErrors> {
+    let mut output = FloatingImage::new(images[0].width(), images[0].height(), String::new());
+    output.set_data(blend_images(images, mode, opacity))?;
+    Ok(output)
+}
+
+/// Blends every image onto the first one in order, returns the pixel values in a vector
+pub fn blend_images<T: Sample>(images: &[DynamicImage], mode: BlendMode, opacity: f32) -> Vec<T> {
+    // Same rgba conversion as combine_images, the bottom layer becomes the buffer being drawn onto.
+    // It's the only copy made, the layers on top are borrowed when they're already rgba
+    let mut backdrop = T::rgba(&images[0]).into_owned();
+    let row_length = images[0].width().max(1) as usize * 4;
+
+    for layer in &images[1..] {
+        let source = T::rgba(layer);
+
+        // Rows don't depend on each other, so they get blended in parallel
+        backdrop
+            .par_chunks_mut(row_length)
+            .zip(source.par_chunks(row_length))
+            .for_each(|(backdrop_row, source_row)| {
+                for (backdrop_pixel, source_pixel) in backdrop_row.chunks_exact_mut(4).zip(source_row.chunks_exact(4)) {
+                    blend_pixel(backdrop_pixel, source_pixel, mode, opacity);
+                }
+            });
+    }
+
+    backdrop
+}
+
+/// Blends a single source pixel onto a backdrop pixel, then composites the result over the backdrop in place
+fn blend_pixel<T: Sample>(backdrop: &mut [T], source: &[T], mode: BlendMode, opacity: f32) {
+    let (cb, ab) = split_pixel(backdrop);
+    let (cs, a_s) = split_pixel(source);
+    let a_s = a_s * opacity;
+
+    // Alpha of the source laid over the backdrop
+    let ao = a_s + ab * (1.0 - a_s);
+    if ao == 0.0 {
+        backdrop.fill(T::default());
+        return;
+    }
+
+    let blended = mode.blend(cb, cs);
+
+    for channel in 0..3 {
+        // Where the backdrop is see through, the source colour is used as is
+        let mixed = (1.0 - ab) * cs[channel] + ab * blended[channel];
+        // Simple alpha compositing of the blended colour over the backdrop
+        let co = (a_s * mixed + ab * cb[channel] * (1.0 - a_s)) / ao;
+        backdrop[channel] = T::from_unit(co);
+    }
+    backdrop[3] = T::from_unit(ao);
+}
+
+/// Splits a pixel into its colour channels and its alpha, each from 0.0 - 1.0
+fn split_pixel<T: Sample>(pixel: &[T]) -> ([f32; 3], f32) {
+    ([pixel[0].to_unit(), pixel[1].to_unit(), pixel[2].to_unit()], pixel[3].to_unit())
+}
+
+/// Luminosity of a colour, weighted the way the eye sees each channel
+fn lum(color: [f32; 3]) -> f32 {
+    0.3 * color[0] + 0.59 * color[1] + 0.11 * color[2]
+}
+
+/// Brings any channel pushed outside of 0.0 - 1.0 back in, without changing the luminosity
+fn clip_color(color: [f32; 3]) -> [f32; 3] {
+    let l = lum(color);
+    let n = color[0].min(color[1]).min(color[2]);
+    let x = color[0].max(color[1]).max(color[2]);
+
+    let mut clipped = color;
+    if n < 0.0 {
+        clipped = clipped.map(|c| l + (c - l) * l / (l - n));
+    }
+    if x > 1.0 {
+        clipped = clipped.map(|c| l + (c - l) * (1.0 - l) / (x - l));
+    }
+    clipped
+}
+
+/// Shifts a colour to the luminosity given
+fn set_lum(color: [f32; 3], l: f32) -> [f32; 3] {
+    let d = l - lum(color);
+    clip_color(color.map(|c| c + d))
+}
+
+/// Saturation of a colour, how far apart its biggest and smallest channels are
+fn sat(color: [f32; 3]) -> f32 {
+    color[0].max(color[1]).max(color[2]) - color[0].min(color[1]).min(color[2])
+}
+
+/// Stretches a colour to the saturation given, the smallest channel ends up at 0.0
+/// and the biggest at s, with the middle one kept in proportion
+fn set_sat(color: [f32; 3], s: f32) -> [f32; 3] {
+    let min = color[0].min(color[1]).min(color[2]);
+    let max = color[0].max(color[1]).max(color[2]);
+
+    if max > min {
+        color.map(|c| (c - min) * s / (max - min))
+    } else {
+        // Grey has no hue to stretch
+        [0.0; 3]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: [f32; 3], expected: [f32; 3]) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-5, "got {:?}, expected {:?}", actual, expected);
+        }
+    }
+
+    fn channel(mode: BlendMode, cb: f32, cs: f32) -> f32 {
+        mode.blend([cb; 3], [cs; 3])[0]
+    }
+
+    #[test]
+    fn simple_separable_modes() {
+        let (cb, cs) = (0.25, 0.5);
+        let expected = [
+            (BlendMode::Normal, 0.5),
+            (BlendMode::Multiply, 0.125),
+            (BlendMode::Screen, 0.625),
+            (BlendMode::Darken, 0.25),
+            (BlendMode::Lighten, 0.5),
+            (BlendMode::Difference, 0.25),
+            (BlendMode::Exclusion, 0.5),
+            (BlendMode::Add, 0.75),
+            (BlendMode::Subtract, 0.0)
+        ];
+        for (mode, value) in expected {
+            assert!((channel(mode, cb, cs) - value).abs() < 1e-6, "{} gave {}", mode.name(), channel(mode, cb, cs));
+        }
+        // Add and subtract stop at the ends of the range
+        assert_eq!(channel(BlendMode::Add, 0.75, 0.5), 1.0);
+        assert_eq!(channel(BlendMode::Subtract, 0.75, 0.5), 0.25);
+    }
+
+    #[test]
+    fn soft_light_follows_each_branch_of_the_spec() {
+        // Source up to 0.5 darkens: cb - (1 - 2cs) * cb * (1 - cb)
+        assert!((channel(BlendMode::SoftLight, 0.25, 0.25) - 0.15625).abs() < 1e-6);
+        // Lighter source over a dark backdrop uses D(cb) = ((16cb - 12)cb + 4)cb
+        assert!((channel(BlendMode::SoftLight, 0.25, 0.75) - 0.375).abs() < 1e-6);
+        // and over a light one D(cb) = sqrt(cb)
+        assert!((channel(BlendMode::SoftLight, 0.64, 1.0) - 0.8).abs() < 1e-6);
+        // Mid grey leaves the backdrop alone
+        assert!((channel(BlendMode::SoftLight, 0.3, 0.5) - 0.3).abs() < 1e-6);
+    }
+
+    #[test]
+    fn dodge_and_burn_edge_cases() {
+        // A black backdrop stays black under dodge, even with a white source
+        assert_eq!(channel(BlendMode::ColorDodge, 0.0, 1.0), 0.0);
+        assert_eq!(channel(BlendMode::ColorDodge, 0.5, 1.0), 1.0);
+        assert_eq!(channel(BlendMode::ColorDodge, 0.25, 0.5), 0.5);
+        assert_eq!(channel(BlendMode::ColorDodge, 0.75, 0.5), 1.0);
+
+        // And a white backdrop stays white under burn, even with a black source
+        assert_eq!(channel(BlendMode::ColorBurn, 1.0, 0.0), 1.0);
+        assert_eq!(channel(BlendMode::ColorBurn, 0.5, 0.0), 0.0);
+        assert_eq!(channel(BlendMode::ColorBurn, 0.75, 0.5), 0.5);
+        assert_eq!(channel(BlendMode::ColorBurn, 0.25, 0.5), 0.0);
+    }
+
+    #[test]
+    fn overlay_is_hard_light_swapped() {
+        assert_eq!(channel(BlendMode::HardLight, 0.5, 0.25), 0.25);
+        assert_eq!(channel(BlendMode::HardLight, 0.5, 0.75), 0.75);
+        assert_eq!(channel(BlendMode::Overlay, 0.25, 0.5), 0.25);
+        assert_eq!(channel(BlendMode::Overlay, 0.75, 0.5), 0.75);
+    }
+
+    #[test]
+    fn opacity_mixes_the_blend_in() {
+        let source = [0.0, 0.0, 0.0, 1.0];
+        for (opacity, expected) in [(0.0, 0.8), (0.25, 0.6), (1.0, 0.0)] {
+            let mut backdrop = [0.8, 0.8, 0.8, 1.0];
+            blend_pixel(&mut backdrop, &source, BlendMode::Normal, opacity);
+            assert_close([backdrop[0], backdrop[1], backdrop[2]], [expected; 3]);
+            assert_eq!(backdrop[3], 1.0);
+        }
+    }
+
+    #[test]
+    fn transparent_backdrop_shows_the_source_as_is() {
+        let mut backdrop = [0.2, 0.4, 0.6, 0.0];
+        blend_pixel(&mut backdrop, &[0.9, 0.3, 0.1, 0.5], BlendMode::Multiply, 1.0);
+        assert_close([backdrop[0], backdrop[1], backdrop[2]], [0.9, 0.3, 0.1]);
+        assert_eq!(backdrop[3], 0.5);
+
+        // Over an opaque backdrop it's the blend mixed in by the source alpha
+        let mut backdrop = [0.5, 0.5, 0.5, 1.0];
+        blend_pixel(&mut backdrop, &[0.5, 1.0, 0.0, 0.5], BlendMode::Multiply, 1.0);
+        assert_close([backdrop[0], backdrop[1], backdrop[2]], [0.375, 0.5, 0.25]);
+        assert_eq!(backdrop[3], 1.0);
+    }
+
+    #[test]
+    fn layers_are_blended_bottom_up() {
+        let layer = |value: u8| DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(2, 1, image::Rgba([value, value, value, 255])));
+        let images = [layer(255), layer(128), layer(128)];
+        // 1.0 * 0.5 * 0.5, with the u8 rounding on the way
+        let blended: Vec<u8> = blend_images(&images, BlendMode::Multiply, 1.0);
+        assert_eq!(blended, [64, 64, 64, 255, 64, 64, 64, 255]);
+    }
+
+    #[test]
+    fn clip_color_keeps_the_luminosity() {
+        let over = clip_color([1.2, 0.5, 0.1]);
+        assert!((lum(over) - lum([1.2, 0.5, 0.1])).abs() < 1e-6);
+        assert!((over[0] - 1.0).abs() < 1e-6);
+
+        let under = clip_color([-0.2, 0.5, 0.6]);
+        assert!((lum(under) - lum([-0.2, 0.5, 0.6])).abs() < 1e-6);
+        assert!(under[0].abs() < 1e-6);
+
+        // Colours already in range aren't touched
+        assert_eq!(clip_color([0.1, 0.2, 0.3]), [0.1, 0.2, 0.3]);
+    }
+
+    #[test]
+    fn set_sat_stretches_between_the_smallest_and_biggest_channel() {
+        assert_close(set_sat([0.2, 0.6, 0.4], 0.5), [0.0, 0.5, 0.25]);
+        assert_close(set_sat([0.9, 0.1, 0.5], 1.0), [1.0, 0.0, 0.5]);
+        assert_eq!(set_sat([0.4, 0.4, 0.4], 0.8), [0.0; 3]);
+    }
+
+    #[test]
+    fn non_separable_modes_swap_the_right_parts() {
+        let (cb, cs) = ([0.2, 0.4, 0.6], [0.9, 0.3, 0.1]);
+        // Luminosity takes the source's, color keeps the backdrop's
+        assert!((lum(BlendMode::Luminosity.blend(cb, cs)) - lum(cs)).abs() < 1e-5);
+        assert!((lum(BlendMode::Color.blend(cb, cs)) - lum(cb)).abs() < 1e-5);
+        // Hue and saturation keep the backdrop's luminosity, saturation takes the source's saturation
+        assert!((lum(BlendMode::Hue.blend(cb, cs)) - lum(cb)).abs() < 1e-5);
+        assert!((sat(BlendMode::Saturation.blend(cb, cs)) - sat(cs)).abs() < 1e-5);
+        // and hue keeps the backdrop's saturation
+        assert!((sat(BlendMode::Hue.blend(cb, cs)) - sat(cb)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn non_separable_modes_on_known_colours() {
+        let (red, grey) = ([1.0, 0.0, 0.0], [0.5, 0.5, 0.5]);
+        // Grey has no hue or saturation to give, so hue and saturation over it leave grey
+        assert_close(BlendMode::Saturation.blend(red, grey), [0.3; 3]);
+        assert_close(BlendMode::Hue.blend(grey, red), grey);
+        // Red's hue and saturation at grey's luminosity
+        assert_close(BlendMode::Color.blend(grey, red), [1.0, 0.2857143, 0.2857143]);
+        assert_close(BlendMode::Luminosity.blend(red, grey), [1.0, 0.2857143, 0.2857143]);
+    }
+
+    #[test]
+    fn non_separable_modes_work_on_any_number_of_layers() {
+        let layer = |color: [u8; 3]| DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba([color[0], color[1], color[2], 255])));
+        let images = [layer([200, 40, 40]), layer([40, 200, 40]), layer([128, 128, 128])];
+        let layered: Vec<u8> = blend_images(&images, BlendMode::Luminosity, 1.0);
+        // Each layer only hands its luminosity on, so the last one decides it
+        let color = [layered[0], layered[1], layered[2]].map(|c| c as f32 / 255.0);
+        assert!((lum(color) - 128.0 / 255.0).abs() < 0.01, "{:?}", layered);
+    }
+}
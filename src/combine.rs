@@ -0,0 +1,437 @@
+use crate::combiner::{CombineOptions, Registry, DEFAULT_COMBINER};
+use crate::composite::Operator;
+use crate::floating_image::{Channels, DynamicFloatingImage, Precision};
+use crate::limits::DecodeLimits;
+use crate::output::{self, EncoderOptions};
+use crate::pattern::Pattern;
+use crate::size::{standardize_size, target_dimensions, SizePolicy};
+use crate::tiled::{self, StripOutput, StripReader};
+use crate::{check_stdin_once, find_image_from_path_with_limits, format_mismatch, ImageDataErrors, Warning, STDIO_PATH};
+use image::{DynamicImage, ImageFormat};
+use rayon::prelude::*;
+use std::io::Write;
+use std::sync::Arc;
+
+/// What gets handed each warning, see Combine::on_warning
+type WarningCallback = Arc<dyn Fn(&Warning) + Send + Sync>;
+
+/// An image to combine, either still on disk or already decoded
+#[derive(Clone)]
+enum Input {
+    Path(String),
+    Image(DynamicImage)
+}
+
+/// Builds up everything needed to combine images, nothing gets read until it's run
+///
+/// ```no_run
+/// # use image_combiner::Combine;
+/// # fn main() -> Result<(), image_combiner::ImageDataErrors> {
+/// Combine::new()
+///     .inputs(["a.png", "b.png", "c.png"])
+///     .mode("alternate")
+///     .pattern("checkerboard:16x16".parse()?)
+///     .save("combined.png")?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Cloning it is how the same settings get run on other inputs, the way batches do
+#[derive(Clone)]
+pub struct Combine {
+    inputs: Vec<Input>,
+    mode: String,
+    options: CombineOptions,
+    size: SizePolicy,
+    encoder: EncoderOptions,
+    memory_budget: Option<u64>,
+    limits: DecodeLimits,
+    on_warning: Option<WarningCallback>
+}
+
+/// What save wrote out
+#[derive(Debug, Clone, PartialEq)]
+pub struct Saved {
+    pub path: String,
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+    /// Whether it was combined a strip at a time to stay inside the memory budget
+    pub in_strips: bool
+}
+
+impl Default for Combine {
+    fn default() -> Self {
+        Combine {
+            inputs: Vec::new(),
+            mode: DEFAULT_COMBINER.to_string(),
+            options: CombineOptions::default(),
+            size: SizePolicy::default(),
+            encoder: EncoderOptions::default(),
+            memory_budget: None,
+            limits: DecodeLimits::default(),
+            on_warning: None
+        }
+    }
+}
+
+impl Combine {
+    /// Alternates pixels, resizing to the smallest image, same as the command line does with no flags
+    pub fn new() -> Self {
+        Combine::default()
+    }
+
+    /// Adds an image to read from a path, the first one added is the bottom layer.
+    /// `-` reads it from standard input
+    pub fn input(mut self, path: impl Into<String>) -> Self {
+        self.inputs.push(Input::Path(path.into()));
+        self
+    }
+
+    /// Adds every path given, in order
+    pub fn inputs<I>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>
+    {
+        self.inputs.extend(paths.into_iter().map(|path| Input::Path(path.into())));
+        self
+    }
+
+    /// Adds an image that's already been decoded
+    pub fn image(mut self, image: DynamicImage) -> Self {
+        self.inputs.push(Input::Image(image));
+        self
+    }
+
+    /// Name of the combiner to use e.g. `alternate`, `multiply` or `over`
+    pub fn mode(mut self, mode: impl Into<String>) -> Self {
+        self.mode = mode.into();
+        self
+    }
+
+    /// All of the combiner settings at once
+    pub fn options(mut self, options: CombineOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// How much each layer shows through when blending or compositing, from 0.0 - 1.0
+    pub fn opacity(mut self, opacity: f32) -> Self {
+        self.options.opacity = opacity.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Which image each pixel is taken from when alternating
+    pub fn pattern(mut self, pattern: Pattern) -> Self {
+        self.options.pattern = pattern;
+        self
+    }
+
+    /// Share of the pixels each image gets with the noise patterns, one for each input.
+    /// They can't be negative or NaN, and with any other pattern or mode they're ignored with a warning
+    pub fn weights(mut self, weights: Vec<f32>) -> Self {
+        self.options.weights = Some(weights);
+        self
+    }
+
+    /// How the images are made the same size before combining
+    pub fn size_policy(mut self, size: SizePolicy) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// The output format and its settings, only used by save and write_to
+    pub fn encoder(mut self, encoder: EncoderOptions) -> Self {
+        self.encoder = encoder;
+        self
+    }
+
+    /// Roughly how many bytes of memory combining can use. When combining the whole images at once
+    /// would need more, they get combined and saved a strip of rows at a time instead. That only works
+    /// when saving PNGs or TIFFs, from inputs that are PNGs, TIFFs or already decoded, and they get resized
+    /// a strip at a time too unless they're floating point. Anything else over the budget fails with [`ImageDataErrors::OverMemoryBudget`]
+    pub fn memory_budget(mut self, bytes: u64) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Limits on the size of the images read from paths, anything over them fails before it's decoded.
+    /// Images given already decoded aren't checked
+    pub fn decode_limits(mut self, limits: DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Gets called with anything worth warning about, e.g. an input named like a different format
+    /// or transparency lost saving to a format without alpha. Nothing gets printed without it
+    pub fn on_warning(mut self, callback: impl Fn(&Warning) + Send + Sync + 'static) -> Self {
+        self.on_warning = Some(Arc::new(callback));
+        self
+    }
+
+    /// Warning for weights that won't make any difference, as they're only used by alternate's noise patterns
+    fn ignored_weights(&self) -> Option<Warning> {
+        let used = self.mode == "alternate" && self.options.pattern.uses_weights();
+        (self.options.weights.is_some() && !used).then_some(Warning::WeightsIgnored)
+    }
+
+    /// Roughly how much memory combining the whole images at once needs, from their headers
+    fn memory_needed(&self) -> Result<u64, ImageDataErrors> {
+        let headers = self
+            .inputs
+            .iter()
+            .map(|input| match input {
+                Input::Path(path) => tiled::header(path),
+                Input::Image(image) => Ok(((image.width(), image.height()), image.color()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(tiled::memory_needed(&headers, self.size.target))
+    }
+
+    /// How much memory combining needs if it's over the budget, None if there's no budget or it fits
+    fn over_budget(&self) -> Result<Option<(u64, u64)>, ImageDataErrors> {
+        let Some(budget) = self.memory_budget else {
+            return Ok(None);
+        };
+        // Nothing to combine is reported by combine, the same as without a budget
+        if self.inputs.len() < 2 {
+            return Ok(None);
+        }
+        // Standard input can only be read once, so there's no peeking at its header to see how big it is
+        if self.inputs.iter().any(|input| matches!(input, Input::Path(path) if path == STDIO_PATH)) {
+            return Err(ImageDataErrors::InvalidArguments(
+                "a memory budget can't be used with an image from standard input, its size isn't known until it's been read whole".to_string()
+            ));
+        }
+
+        let needed = self.memory_needed()?;
+        Ok((needed > budget).then_some((needed, budget)))
+    }
+
+    /// Combines and saves a strip at a time, for when the whole images don't fit in the budget
+    fn save_in_strips(self, path: String, needed: u64, budget: u64) -> Result<Saved, ImageDataErrors> {
+        let over = |reason: String| ImageDataErrors::OverMemoryBudget(needed, budget, reason);
+        let registry = Registry::new(&self.options);
+        let combiner = registry.get(&self.mode)?;
+        warn(&self.on_warning, self.ignored_weights());
+
+        let mut input_format = None;
+        let mut readers = Vec::with_capacity(self.inputs.len());
+        for input in self.inputs {
+            readers.push(match input {
+                Input::Path(path) => {
+                    let reader = StripReader::open(&path)?.ok_or_else(|| {
+                        over(format!("`{}` has to be decoded whole, only PNGs that aren't interlaced and TIFFs can be read a strip at a time", path))
+                    })?;
+                    if let Some(format) = reader.format() {
+                        warn(&self.on_warning, format_mismatch(&path, format));
+                        input_format = input_format.or(Some(format));
+                    }
+
+                    // Strips are small, but the limits are about what images get accepted at all
+                    let (width, height) = reader.dimensions();
+                    if let Err(limit) = self.limits.check(width, height) {
+                        return Err(ImageDataErrors::ExceedsDecodeLimit(path, (width, height), limit));
+                    }
+                    reader
+                },
+                Input::Image(image) => StripReader::from_image(image)
+            });
+        }
+
+        let dimensions: Vec<(u32, u32)> = readers.iter().map(StripReader::dimensions).collect();
+        let (width, height) = target_dimensions(&dimensions, self.size.target);
+        let readers = readers
+            .into_iter()
+            .map(|reader| {
+                if reader.dimensions() == (width, height) {
+                    Ok(reader)
+                } else if Precision::of_color(reader.color()) == Precision::F32 {
+                    Err(over("floating point images are resized relative to their brightest pixel, which needs the whole image".to_string()))
+                } else {
+                    reader.fit(width, height, &self.size)
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let format = output::output_format(&path, &self.encoder, input_format.unwrap_or(ImageFormat::Png))?;
+        if !output::supports_strips(format) {
+            return Err(over(format!("only PNGs and TIFFs can be saved a strip at a time, not {:?}", format)));
+        }
+        if path == STDIO_PATH && format == ImageFormat::Tiff {
+            return Err(over("TIFFs have to be saved to a file to be written a strip at a time, only PNGs can go to standard output".to_string()));
+        }
+
+        // Most compositing operators can cut holes in opaque images
+        let adds_alpha = Operator::ALL.iter().any(|operator| operator.name() == self.mode);
+        let output = StripOutput { path: &path, format, encoder: &self.encoder, adds_alpha };
+        let (width, height) = tiled::combine_in_strips(readers, combiner, output, budget)?;
+
+        Ok(Saved { path, format, width, height, in_strips: true })
+    }
+
+    /// Decodes, resizes and combines the images. Returns the combined image, the format of the first input
+    /// read from a path (for when nothing else says what format to save in) and the encoder options
+    fn combine(self) -> Result<(DynamicFloatingImage, Option<ImageFormat>, EncoderOptions), ImageDataErrors> {
+        // Look the combiner up before doing any decoding, so a typo in the name fails fast
+        let registry = Registry::new(&self.options);
+        let combiner = registry.get(&self.mode)?;
+        warn(&self.on_warning, self.ignored_weights());
+
+        // Alternating pixels needs at least two sources to alternate between
+        if self.inputs.len() < 2 {
+            return Err(ImageDataErrors::NotEnoughImages(self.inputs.len()));
+        }
+        check_stdin_once(self.inputs.iter().filter_map(|input| match input {
+            Input::Path(path) => Some(path.as_str()),
+            Input::Image(_) => None
+        }))?;
+
+        // Inputs can be any mix of formats, they all get decoded to the same pixel buffer when combining.
+        // They're decoded in parallel, then gone through in order so the error reported is always the first input's
+        let limits = self.limits;
+        let decoded: Vec<_> = self
+            .inputs
+            .into_par_iter()
+            .map(|input| match input {
+                Input::Path(path) => find_image_from_path_with_limits(path.clone(), &limits).map(|(image, format)| (image, Some((path, format)))),
+                Input::Image(image) => Ok((image, None))
+            })
+            .collect();
+
+        let mut images = Vec::with_capacity(decoded.len());
+        let mut input_format = None;
+        for result in decoded {
+            let (image, source) = result?;
+            images.push(image);
+            if let Some((path, format)) = source {
+                warn(&self.on_warning, format_mismatch(&path, format));
+                input_format = input_format.or(Some(format));
+            }
+        }
+
+        // Work out the precision before resizing, as resizing in linear light turns everything into floating point
+        let precision = Precision::of(&images);
+        // Same goes for the channels, greyscale inputs shouldn't come out as rgba
+        let channels = Channels::of(&images);
+
+        // Redeclare(shadow) images from resizing result
+        let images = standardize_size(images, &self.size);
+
+        let mut output = combiner.combine(&images, precision)?;
+        output.set_channels(channels);
+
+        Ok((output, input_format, self.encoder))
+    }
+
+    /// Combines the images and returns the result without saving it. The whole image is returned,
+    /// so this fails when it doesn't fit in the memory budget
+    pub fn run(self) -> Result<DynamicFloatingImage, ImageDataErrors> {
+        if let Some((needed, budget)) = self.over_budget()? {
+            return Err(ImageDataErrors::OverMemoryBudget(needed, budget, "only saving to a file can be done a strip at a time".to_string()));
+        }
+        self.combine().map(|(output, _, _)| output)
+    }
+
+    /// Combines the images and encodes the result into writer, in the format given.
+    /// Fails when it doesn't fit in the memory budget, the same as run
+    pub fn write_to<W: Write>(self, writer: W, format: ImageFormat) -> Result<DynamicFloatingImage, ImageDataErrors> {
+        if let Some((needed, budget)) = self.over_budget()? {
+            return Err(ImageDataErrors::OverMemoryBudget(needed, budget, "only saving to a file can be done a strip at a time".to_string()));
+        }
+        let on_warning = self.on_warning.clone();
+        let (output, _, encoder) = self.combine()?;
+        warn(&on_warning, output::encode(&output, format, &encoder, writer)?);
+        Ok(output)
+    }
+
+    /// Combines the images and saves the result to path. The format comes from the encoder options
+    /// or the path's extension, only falling back on the first input's format when the path has no extension at all.
+    /// A path of `-` writes to standard output, which needs the format set in the encoder options.
+    /// Images too big for the memory budget get saved a strip at a time
+    pub fn save(self, path: impl Into<String>) -> Result<Saved, ImageDataErrors> {
+        let path = path.into();
+        // Standard output needs the format given, better to find out it's missing before decoding anything.
+        // The fallback isn't used for that, the real format gets worked out again once the inputs' is known
+        output::output_format(&path, &self.encoder, ImageFormat::Png)?;
+        if let Some((needed, budget)) = self.over_budget()? {
+            return self.save_in_strips(path, needed, budget);
+        }
+
+        let on_warning = self.on_warning.clone();
+        let (mut output, input_format, encoder) = self.combine()?;
+        // Decoded images don't have a format, PNG can store anything they could be
+        let format = output::output_format(&path, &encoder, input_format.unwrap_or(ImageFormat::Png))?;
+
+        output.set_name(path.clone());
+        warn(&on_warning, output::save(&output, format, &encoder)?);
+
+        let (width, height) = output.dimensions();
+        Ok(Saved { path, format, width, height, in_strips: false })
+    }
+}
+
+/// Hands the warning to the on_warning callback, when there's both
+fn warn(on_warning: &Option<WarningCallback>, warning: Option<Warning>) {
+    if let (Some(callback), Some(warning)) = (on_warning, warning) {
+        callback(&warning);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+    use std::sync::Mutex;
+
+    fn filled(colour: [u8; 4]) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 3, Rgba(colour)))
+    }
+
+    #[test]
+    fn run_write_to_and_save_give_the_same_image() {
+        let (red, blue) = ([255, 0, 0, 255], [0, 0, 255, 128]);
+        let combine = Combine::new().image(filled(red)).image(filled(blue));
+
+        // The default single pixel checkerboard
+        let output = combine.clone().run().unwrap();
+        assert_eq!(output.dimensions(), (4, 3));
+        let expected: Vec<u8> = (0..3).flat_map(|y| (0..4).flat_map(move |x| if (x + y) % 2 == 0 { red } else { blue })).collect();
+        assert_eq!(output.samples::<u8>().into_owned(), expected);
+
+        let mut written = Vec::new();
+        combine.clone().write_to(&mut written, ImageFormat::Png).unwrap();
+        assert_eq!(image::load_from_memory(&written).unwrap().into_bytes(), expected);
+
+        // Decoded images have no format, so a path without an extension is saved as PNG
+        let path = std::env::temp_dir().join(format!("image-combiner-{}-builder", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let saved = combine.save(&path).unwrap();
+        let read = std::fs::read(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(saved, Saved { path, format: ImageFormat::Png, width: 4, height: 3, in_strips: false });
+        assert_eq!(image::load_from_memory_with_format(&read.unwrap(), ImageFormat::Png).unwrap().into_bytes(), expected);
+    }
+
+    #[test]
+    fn warnings_go_to_the_callback() {
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let collected = warnings.clone();
+        let combine = Combine::new()
+            .image(filled([255, 0, 0, 255]))
+            .image(filled([0, 0, 255, 128]))
+            .on_warning(move |warning| collected.lock().unwrap().push(warning.clone()));
+
+        combine.clone().write_to(Vec::new(), ImageFormat::Png).unwrap();
+        assert!(warnings.lock().unwrap().is_empty());
+        combine.write_to(Vec::new(), ImageFormat::Jpeg).unwrap();
+        assert_eq!(*warnings.lock().unwrap(), [Warning::TransparencyFlattened(ImageFormat::Jpeg, EncoderOptions::default().background)]);
+    }
+
+    #[test]
+    fn combining_needs_two_images() {
+        assert!(matches!(Combine::new().image(filled([0; 4])).run(), Err(ImageDataErrors::NotEnoughImages(1))));
+        assert!(matches!(Combine::new().image(filled([0; 4])).image(filled([0; 4])).mode("nope").run(), Err(ImageDataErrors::UnknownCombiner(_))));
+    }
+}
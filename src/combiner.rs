@@ -0,0 +1,268 @@
+use crate::blend::{Blend, BlendMode};
+use crate::composite::{Composite, Operator};
+use crate::floating_image::{DynamicFloatingImage, FloatingImage, Precision, Sample};
+use crate::pattern::Pattern;
+use crate::ImageDataErrors;
+use image::{DynamicImage, GenericImageView};
+use rayon::prelude::*;
+use std::borrow::Cow;
+
+/// Name of the combiner used when none is picked on the command line
+pub const DEFAULT_COMBINER: &str = "alternate";
+
+/// Settings the combiners are built with, filled in from the command line
+#[derive(Debug, Clone)]
+pub struct CombineOptions {
+    // How much each layer shows through the ones below it when blending or compositing, from 0.0 - 1.0
+    pub opacity: f32,
+    // Which image each pixel is taken from when alternating
+    pub pattern: Pattern,
+    // How big a share of the pixels each image gets with the noise patterns, every image weighs the same if not set
+    pub weights: Option<Vec<f32>>
+}
+
+impl Default for CombineOptions {
+    fn default() -> Self {
+        CombineOptions { opacity: 1.0, pattern: Pattern::default(), weights: None }
+    }
+}
+
+/// A way of merging the (already equally sized) input images into a single output image
+pub trait Combiner {
+    /// Name the combiner is selected by on the command line
+    fn name(&self) -> &'static str;
+
+    /// Takes in the images, all of the same dimensions, returns the combined image
+    /// worked out at the precision given. The returned image has no name yet, that gets set by whoever saves it
+    fn combine(&self, images: &[DynamicImage], precision: Precision) -> Result<DynamicFloatingImage, ImageDataErrors>;
+
+    /// Combines a strip of rows out of bigger images, used when they're too big to combine all at once.
+    /// top is the row the strip starts on and dimensions is the size of the whole image.
+    /// Combiners that treat every pixel the same wherever it is don't need to do anything different
+    fn combine_strip(&self, images: &[DynamicImage], precision: Precision, _top: u32, _dimensions: (u32, u32)) -> Result<DynamicFloatingImage, ImageDataErrors> {
+        self.combine(images, precision)
+    }
+}
+
+/// Holds every combiner that can be picked by name
+pub struct Registry {
+    combiners: Vec<Box<dyn Combiner>>
+}
+
+impl Registry {
+    /// Registry with all of the built in combiners, set up with the options given
+    pub fn new(options: &CombineOptions) -> Self {
+        let mut registry = Registry { combiners: Vec::new() };
+        registry.register(Box::new(Alternate {
+            pattern: options.pattern.clone(),
+            weights: options.weights.clone()
+        }));
+
+        for mode in BlendMode::ALL {
+            registry.register(Box::new(Blend { mode, opacity: options.opacity }));
+        }
+        for operator in Operator::ALL {
+            registry.register(Box::new(Composite { operator, opacity: options.opacity }));
+        }
+        registry
+    }
+
+    /// Adds a combiner, replacing any existing one with the same name
+    pub fn register(&mut self, combiner: Box<dyn Combiner>) {
+        self.combiners.retain(|existing| existing.name() != combiner.name());
+        self.combiners.push(combiner);
+    }
+
+    /// Names of every combiner, in the order they were registered
+    pub fn names(&self) -> Vec<&'static str> {
+        self.combiners.iter().map(|combiner| combiner.name()).collect()
+    }
+
+    /// Looks up a combiner by the name it was registered with
+    pub fn get(&self, name: &str) -> Result<&dyn Combiner, ImageDataErrors> {
+        self.combiners
+            .iter()
+            .find(|combiner| combiner.name() == name)
+            .map(|combiner| combiner.as_ref())
+            .ok_or_else(|| ImageDataErrors::UnknownCombiner(name.to_string()))
+    }
+}
+
+/// Alternates pixels between each of the images, following the pattern given
+pub struct Alternate {
+    pub pattern: Pattern,
+    pub weights: Option<Vec<f32>>
+}
+
+impl Combiner for Alternate {
+    fn name(&self) -> &'static str {
+        "alternate"
+    }
+
+    fn combine(&self, images: &[DynamicImage], precision: Precision) -> Result<DynamicFloatingImage, ImageDataErrors> {
+        self.combine_strip(images, precision, 0, images[0].dimensions())
+    }
+
+    // The pattern is worked out from where each pixel is in the whole image, so strips line up with each other
+    fn combine_strip(&self, images: &[DynamicImage], precision: Precision, top: u32, dimensions: (u32, u32)) -> Result<DynamicFloatingImage, ImageDataErrors> {
+        // One weight is needed for every image, and they can't all be zero. NaN isn't >= 0 so it's caught too
+        let weights = match &self.weights {
+            Some(weights)
+                if weights.len() != images.len()
+                    || weights.iter().any(|weight| !(*weight >= 0.0 && weight.is_finite()))
+                    || weights.iter().sum::<f32>() <= 0.0 =>
+            {
+                return Err(ImageDataErrors::InvalidWeights(weights.clone()))
+            },
+            Some(weights) => weights.clone(),
+            None => vec![1.0; images.len()]
+        };
+
+        let placement = (top, dimensions);
+        Ok(match precision {
+            Precision::U8 => alternate::<u8>(images, &self.pattern, &weights, placement)?.into_dynamic(),
+            Precision::U16 => alternate::<u16>(images, &self.pattern, &weights, placement)?.into_dynamic(),
+            Precision::F32 => alternate::<f32>(images, &self.pattern, &weights, placement)?.into_dynamic()
+        })
+    }
+}
+
+/// Alternates the images with samples of type T. placement is the row the images start on
+/// and the size of the whole image, for when they're only a strip out of it
+fn alternate<T: Sample>(images: &[DynamicImage], pattern: &Pattern, weights: &[f32], placement: (u32, (u32, u32))) -> Result<FloatingImage<T>, ImageDataErrors> {
+    let mut output = FloatingImage::new(images[0].width(), images[0].height(), String::new());
+    output.set_data(combine_images(images, pattern, weights, placement))?;
+    Ok(output)
+}
+
+// Takes in the images, the pattern to alternate them in and each image's weight, returns the pixel values in a vector
+fn combine_images<T: Sample>(images: &[DynamicImage], pattern: &Pattern, weights: &[f32], placement: (u32, (u32, u32))) -> Vec<T> {
+    // Sample::rgba borrows the pixels of images that are already rgba of type T,
+    // anything else gets converted once up front so the loop below only ever copies
+    let sources: Vec<Cow<[T]>> = images.iter().map(T::rgba).collect();
+    let sources: Vec<&[T]> = sources.iter().map(|source| source.as_ref()).collect();
+
+    let mut combined_data = vec![T::default(); sources[0].len()];
+    alternate_pixels(&sources, &mut combined_data, images[0].width(), placement, pattern, weights);
+    combined_data
+}
+
+/// Fills output with pixels from whichever source the pattern picks, without allocating.
+/// Each row is filled in on whichever thread picks it up, the pattern only depends on (x, y)
+/// so the output is the same no matter how many threads there are
+fn alternate_pixels<T: Sample>(sources: &[&[T]], output: &mut [T], width: u32, placement: (u32, (u32, u32)), pattern: &Pattern, weights: &[f32]) {
+    let (top, dimensions) = placement;
+    let width = width.max(1) as usize;
+    // 4 because rgba
+    let row_length = width * 4;
+
+    output.par_chunks_mut(row_length).enumerate().for_each(|(row_index, row)| {
+        let row_start = row_index * row_length;
+        let y = top + row_index as u32;
+
+        // Pixels next to each other usually come from the same source (rows, columns, tiles...),
+        // so runs of them are copied in one go rather than pixel by pixel
+        let mut run_start = 0;
+        let mut run_source = pattern.source(0, y, dimensions, weights);
+        for x in 1..=width {
+            let source = if x < width { pattern.source(x as u32, y, dimensions, weights) } else { usize::MAX };
+            if source != run_source {
+                let (start, end) = (run_start * 4, x * 4);
+                row[start..end].copy_from_slice(&sources[run_source][row_start + start..row_start + end]);
+                run_start = x;
+                run_source = source;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::size::{standardize_size, SizePolicy};
+    use image::{Rgb, RgbImage};
+
+    /// Stands in for a combiner someone else wrote, it always gives back the first image
+    struct First;
+
+    impl Combiner for First {
+        fn name(&self) -> &'static str {
+            "alternate"
+        }
+
+        fn combine(&self, images: &[DynamicImage], _precision: Precision) -> Result<DynamicFloatingImage, ImageDataErrors> {
+            Ok(FloatingImage::<u8>::from_image(&images[0], String::new()).into_dynamic())
+        }
+    }
+
+    #[test]
+    fn combiners_are_looked_up_by_name() {
+        let registry = Registry::new(&CombineOptions::default());
+        let names = registry.names();
+        assert_eq!(names[0], DEFAULT_COMBINER);
+        for name in ["multiply", "soft-light", "hue", "over", "xor", "plus"] {
+            assert_eq!(registry.get(name).unwrap().name(), name);
+        }
+        // Names are matched exactly
+        for name in ["nope", "Multiply", "", " xor"] {
+            assert!(matches!(registry.get(name), Err(ImageDataErrors::UnknownCombiner(unknown)) if unknown == name), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn registering_a_name_again_replaces_it() {
+        let mut registry = Registry::new(&CombineOptions::default());
+        let count = registry.names().len();
+        registry.register(Box::new(First));
+
+        // Still only one of each name, the new one goes on the end
+        assert_eq!(registry.names().len(), count);
+        assert_eq!(registry.names().last(), Some(&"alternate"));
+        let images = [DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([9, 9, 9]))), DynamicImage::ImageRgb8(RgbImage::new(2, 2))];
+        let combined = registry.get("alternate").unwrap().combine(&images, Precision::U8).unwrap();
+        assert!(combined.samples::<u8>().chunks_exact(4).all(|pixel| pixel == [9, 9, 9, 255]));
+    }
+
+    #[test]
+    fn weights_have_to_be_usable() {
+        let images = vec![DynamicImage::ImageRgb8(RgbImage::new(4, 4)); 2];
+        let combine = |weights: Vec<f32>| Alternate { pattern: Pattern::Random(1), weights: Some(weights) }.combine(&images, Precision::U8);
+
+        assert!(combine(vec![3.0, 1.0]).is_ok());
+        assert!(combine(vec![0.0, 1.0]).is_ok());
+        for weights in [vec![1.0], vec![0.0, 0.0], vec![-1.0, 2.0], vec![f32::NAN, 1.0], vec![f32::INFINITY, 1.0]] {
+            assert!(matches!(combine(weights), Err(ImageDataErrors::InvalidWeights(_))));
+        }
+    }
+
+    #[test]
+    fn output_is_the_same_on_any_number_of_threads() {
+        // Different sizes so they get resized too, with something in them for the resampling to mix
+        let images: Vec<_> = [(64, 48), (80, 40), (50, 61)]
+            .iter()
+            .enumerate()
+            .map(|(index, &(width, height))| {
+                DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+                    Rgb([(x * 7 + y * 3) as u8, (x * y + index as u32 * 40) as u8, (x ^ y) as u8])
+                }))
+            })
+            .collect();
+        let options = CombineOptions { opacity: 0.7, pattern: Pattern::Random(3), weights: Some(vec![1.0, 2.0, 3.0]) };
+
+        let run = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            pool.install(|| {
+                let resized = standardize_size(images.clone(), &SizePolicy::default());
+                let registry = Registry::new(&options);
+                ["alternate", "multiply", "soft-light", "xor"]
+                    .iter()
+                    .map(|name| registry.get(name).unwrap().combine(&resized, Precision::U8).unwrap().samples::<u8>().into_owned())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        let single = run(1);
+        assert_eq!(single, run(4));
+        assert_eq!(single, run(7));
+    }
+}
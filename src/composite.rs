@@ -0,0 +1,221 @@
+use crate::combiner::Combiner;
+use crate::floating_image::{DynamicFloatingImage, FloatingImage, Precision, Sample};
+use crate::ImageDataErrors;
+use image::DynamicImage;
+use rayon::prelude::*;
+
+/// Porter-Duff operators, deciding how much of the source (the layer on top)
+/// and the destination (the layers below) ends up in the result based on their alpha
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Over,
+    In,
+    Out,
+    Atop,
+    Xor,
+    Plus,
+    Src,
+    Dst,
+    Clear
+}
+
+impl Operator {
+    /// Every operator, used to fill up the combiner registry
+    pub const ALL: [Operator; 9] = [
+        Operator::Over,
+        Operator::In,
+        Operator::Out,
+        Operator::Atop,
+        Operator::Xor,
+        Operator::Plus,
+        Operator::Src,
+        Operator::Dst,
+        Operator::Clear
+    ];
+
+    /// Name the operator is picked by on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            Operator::Over => "over",
+            Operator::In => "in",
+            Operator::Out => "out",
+            Operator::Atop => "atop",
+            Operator::Xor => "xor",
+            Operator::Plus => "plus",
+            Operator::Src => "src",
+            Operator::Dst => "dst",
+            Operator::Clear => "clear"
+        }
+    }
+
+    /// Fractions of the source and of the destination that make it into the result,
+    /// given the source alpha (a_s) and destination alpha (ad)
+    fn fractions(self, a_s: f32, ad: f32) -> (f32, f32) {
+        match self {
+            Operator::Over => (1.0, 1.0 - a_s),
+            Operator::In => (ad, 0.0),
+            Operator::Out => (1.0 - ad, 0.0),
+            Operator::Atop => (ad, 1.0 - a_s),
+            Operator::Xor => (1.0 - ad, 1.0 - a_s),
+            Operator::Plus => (1.0, 1.0),
+            Operator::Src => (1.0, 0.0),
+            Operator::Dst => (0.0, 1.0),
+            Operator::Clear => (0.0, 0.0)
+        }
+    }
+}
+
+/// Composites the images on top of each other, the first image is the bottom layer (destination)
+/// and every image after it is the source composited onto the result so far
+pub struct Composite {
+    pub operator: Operator,
+    // Scales the alpha of each layer on top, from 0.0 - 1.0
+    pub opacity: f32
+}
+
+impl Combiner for Composite {
+    fn name(&self) -> &'static str {
+        self.operator.name()
+    }
+
+    fn combine(&self, images: &[DynamicImage], precision: Precision) -> Result<DynamicFloatingImage, ImageDataErrors> {
+        Ok(match precision {
+            Precision::U8 => composite::<u8>(images, self.operator, self.opacity)?.into_dynamic(),
+            Precision::U16 => composite::<u16>(images, self.operator, self.opacity)?.into_dynamic(),
+            Precision::F32 => composite::<f32>(images, self.operator, self.opacity)?.into_dynamic()
+        })
+    }
+}
+
+/// Composites the images with samples of type T
+fn composite<T: Sample>(images: &[DynamicImage], operator: Operator, opacity: f32) -> Result<FloatingImage<T>, ImageDataErrors> {
+    let mut output = FloatingImage::new(images[0].width(), images[0].height(), String::new());
+    output.set_data(composite_images(images, operator, opacity))?;
+    Ok(output)
+}
+
+/// Composites every image onto the first one in order, returns the pixel values in a vector
+pub fn composite_images<T: Sample>(images: &[DynamicImage], operator: Operator, opacity: f32) -> Vec<T> {
+    let mut destination = T::rgba(&images[0]).into_owned();
+    let row_length = images[0].width().max(1) as usize * 4;
+
+    for layer in &images[1..] {
+        let source = T::rgba(layer);
+
+        // Rows are composited in parallel, the same as blending
+        destination
+            .par_chunks_mut(row_length)
+            .zip(source.par_chunks(row_length))
+            .for_each(|(destination_row, source_row)| {
+                for (destination_pixel, source_pixel) in destination_row.chunks_exact_mut(4).zip(source_row.chunks_exact(4)) {
+                    composite_pixel(destination_pixel, source_pixel, operator, opacity);
+                }
+            });
+    }
+
+    destination
+}
+
+/// Composites a single source pixel onto a destination pixel in place.
+/// The maths is done on premultiplied colour (colour * alpha), then divided back out for saving
+fn composite_pixel<T: Sample>(destination: &mut [T], source: &[T], operator: Operator, opacity: f32) {
+    let (cd, ad) = premultiply(destination, 1.0);
+    let (cs, a_s) = premultiply(source, opacity);
+    let (fa, fb) = operator.fractions(a_s, ad);
+
+    // Plus can go over 1.0 where both layers are opaque, so it gets clamped
+    let ao = (fa * a_s + fb * ad).min(1.0);
+    if ao == 0.0 {
+        destination.fill(T::default());
+        return;
+    }
+
+    for channel in 0..3 {
+        // The colour of plus gets clamped with the alpha, or unpremultiplying would take it past 1.0.
+        // Floating point samples aren't clamped when they're saved, so it has to be done here
+        let co = (fa * cs[channel] + fb * cd[channel]).min(ao);
+        destination[channel] = T::from_unit(co / ao);
+    }
+    destination[3] = T::from_unit(ao);
+}
+
+/// Splits a pixel into its premultiplied colour channels and its alpha, each from 0.0 - 1.0.
+/// The alpha is scaled by opacity first
+fn premultiply<T: Sample>(pixel: &[T], opacity: f32) -> ([f32; 3], f32) {
+    let a = pixel[3].to_unit() * opacity;
+    ([pixel[0].to_unit() * a, pixel[1].to_unit() * a, pixel[2].to_unit() * a], a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn composite(operator: Operator, source: [f32; 4], destination: [f32; 4], opacity: f32) -> [f32; 4] {
+        let mut result = destination;
+        composite_pixel(&mut result, &source, operator, opacity);
+        result
+    }
+
+    fn assert_close(actual: [f32; 4], expected: [f32; 4]) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6, "got {:?}, expected {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn fractions_match_porter_duff() {
+        let (a_s, ad) = (0.25, 0.5);
+        let expected = [
+            (Operator::Over, (1.0, 0.75)),
+            (Operator::In, (0.5, 0.0)),
+            (Operator::Out, (0.5, 0.0)),
+            (Operator::Atop, (0.5, 0.75)),
+            (Operator::Xor, (0.5, 0.75)),
+            (Operator::Plus, (1.0, 1.0)),
+            (Operator::Src, (1.0, 0.0)),
+            (Operator::Dst, (0.0, 1.0)),
+            (Operator::Clear, (0.0, 0.0))
+        ];
+        for (operator, fractions) in expected {
+            assert_eq!(operator.fractions(a_s, ad), fractions, "{:?}", operator);
+        }
+    }
+
+    #[test]
+    fn operators_on_known_pixels() {
+        let half_red = [1.0, 0.0, 0.0, 0.5];
+        let half_blue = [0.0, 0.0, 1.0, 0.5];
+        let red = [1.0, 0.0, 0.0, 1.0];
+        let blue = [0.0, 0.0, 1.0, 1.0];
+
+        assert_close(composite(Operator::Over, half_red, blue, 1.0), [0.5, 0.0, 0.5, 1.0]);
+        assert_close(composite(Operator::Over, half_red, half_blue, 1.0), [2.0 / 3.0, 0.0, 1.0 / 3.0, 0.75]);
+        assert_close(composite(Operator::In, red, half_blue, 1.0), [1.0, 0.0, 0.0, 0.5]);
+        assert_close(composite(Operator::Out, red, [0.0, 0.0, 1.0, 0.25], 1.0), [1.0, 0.0, 0.0, 0.75]);
+        assert_close(composite(Operator::Atop, half_red, half_blue, 1.0), [0.5, 0.0, 0.5, 0.5]);
+        assert_close(composite(Operator::Plus, half_red, half_blue, 1.0), [0.5, 0.0, 0.5, 1.0]);
+        assert_close(composite(Operator::Src, half_red, blue, 1.0), half_red);
+        assert_close(composite(Operator::Dst, half_red, blue, 1.0), blue);
+    }
+
+    #[test]
+    fn nothing_left_is_fully_transparent() {
+        // Both opaque, so xor leaves neither
+        assert_eq!(composite(Operator::Xor, [1.0, 0.0, 0.0, 1.0], [0.0, 0.0, 1.0, 1.0], 1.0), [0.0; 4]);
+        assert_eq!(composite(Operator::Clear, [1.0, 0.0, 0.0, 1.0], [0.0, 0.0, 1.0, 1.0], 1.0), [0.0; 4]);
+    }
+
+    #[test]
+    fn opacity_scales_the_source_alpha() {
+        assert_close(composite(Operator::Over, [1.0, 0.0, 0.0, 1.0], [0.0, 0.0, 1.0, 1.0], 0.5), [0.5, 0.0, 0.5, 1.0]);
+        assert_close(composite(Operator::Over, [1.0, 0.0, 0.0, 1.0], [0.0, 0.0, 1.0, 1.0], 0.0), [0.0, 0.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn plus_stays_in_range() {
+        // Opaque layers added together would be 2.0 before clamping
+        assert_close(composite(Operator::Plus, [1.0, 0.5, 0.0, 1.0], [1.0, 0.75, 0.0, 1.0], 1.0), [1.0, 1.0, 0.0, 1.0]);
+        // Below full alpha nothing needs clamping, the colours are added weighted by their alpha
+        assert_close(composite(Operator::Plus, [1.0, 0.0, 0.0, 0.25], [0.0, 0.0, 1.0, 0.5], 1.0), [1.0 / 3.0, 0.0, 2.0 / 3.0, 0.75]);
+    }
+}
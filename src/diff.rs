@@ -0,0 +1,89 @@
+use image::DynamicImage;
+
+/// How different two images of the same size are. Differences are per channel, from 0.0 - 1.0
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiffStats {
+    pub pixels: u64,
+    // Pixels where any channel (alpha included) is further apart than the threshold
+    pub differing_pixels: u64,
+    pub max_difference: f32,
+    pub mean_difference: f32,
+    // Peak signal to noise ratio in decibels, infinite when the images are the same
+    pub psnr: f64
+}
+
+/// Compares two images pixel by pixel, they need to be the same size already.
+/// The threshold is how far apart a channel can be before the pixel counts as different
+pub fn diff_stats(first: &DynamicImage, second: &DynamicImage, threshold: f32) -> DiffStats {
+    let first = first.to_rgba32f();
+    let second = second.to_rgba32f();
+
+    let mut differing_pixels = 0;
+    let mut max_difference: f32 = 0.0;
+    let mut total_difference = 0.0;
+    let mut total_squared = 0.0;
+
+    for (a, b) in first.pixels().zip(second.pixels()) {
+        let mut pixel_difference: f32 = 0.0;
+        for channel in 0..4 {
+            let difference = (a.0[channel] - b.0[channel]).abs();
+            pixel_difference = pixel_difference.max(difference);
+            total_difference += difference as f64;
+            total_squared += (difference * difference) as f64;
+        }
+
+        if pixel_difference > threshold {
+            differing_pixels += 1;
+        }
+        max_difference = max_difference.max(pixel_difference);
+    }
+
+    let pixels = first.width() as u64 * first.height() as u64;
+    let samples = (pixels * 4).max(1) as f64;
+    let mean_squared = total_squared / samples;
+
+    DiffStats {
+        pixels,
+        differing_pixels,
+        max_difference,
+        mean_difference: (total_difference / samples) as f32,
+        // The peak value is 1.0, so the usual 10 * log10(peak^2 / mse) is just this
+        psnr: if mean_squared > 0.0 { -10.0 * mean_squared.log10() } else { f64::INFINITY }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn identical_images_have_no_difference() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(4, 4, |x, y| Rgba([x as u8 * 60, y as u8 * 60, 7, 255])));
+        let stats = diff_stats(&image, &image, 0.0);
+        assert_eq!(stats.pixels, 16);
+        assert_eq!(stats.differing_pixels, 0);
+        assert_eq!((stats.max_difference, stats.mean_difference), (0.0, 0.0));
+        assert_eq!(stats.psnr, f64::INFINITY);
+    }
+
+    #[test]
+    fn known_differences_give_known_stats() {
+        let black = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 1, Rgba([0, 0, 0, 255])));
+        let mut changed = black.to_rgba8();
+        changed.put_pixel(0, 0, Rgba([255, 0, 0, 255]));
+        let changed = DynamicImage::ImageRgba8(changed);
+
+        // One channel out of 8 is 1.0 off, so the mean squared error is 1/8
+        let stats = diff_stats(&black, &changed, 0.0);
+        assert_eq!(stats.pixels, 2);
+        assert_eq!(stats.differing_pixels, 1);
+        assert_eq!(stats.max_difference, 1.0);
+        assert_eq!(stats.mean_difference, 0.125);
+        assert!((stats.psnr - -10.0 * 0.125f64.log10()).abs() < 1e-9, "{}", stats.psnr);
+        assert!((stats.psnr - 9.031).abs() < 0.001);
+
+        // A difference has to be over the threshold to count
+        assert_eq!(diff_stats(&black, &changed, 1.0).differing_pixels, 0);
+    }
+}
@@ -0,0 +1,316 @@
+use crate::limits::Limit;
+use crate::output::{format_name, Background};
+use crate::tiled::format_size;
+use image::{ImageError, ImageFormat};
+use std::error::Error;
+use std::fmt;
+
+/// Everything that can go wrong, from bad arguments to images that can't be read or saved.
+/// Variants about a file hold its path first
+#[derive(Debug)]
+pub enum ImageDataErrors {
+    BufferTooSmall,
+    NotEnoughImages(usize),
+    UnknownCombiner(String),
+    InvalidPattern(String),
+    InvalidWeights(Vec<f32>),
+    InvalidSizePolicy(String),
+    InvalidFilter(String),
+    InvalidEncoderOption(String),
+    InvalidMemoryBudget(String),
+    // Roughly how many bytes combining needs, the budget, then why it can't be done a strip at a time instead
+    OverMemoryBudget(u64, u64, String),
+    // What the command line parser said was wrong with the arguments
+    InvalidArguments(String),
+    MissingOutput,
+    // The output path, when it's standard output or its extension isn't a format that can be saved,
+    // and there's no format given
+    MissingOutputFormat(String),
+    // Name or path the recipe was asked for by
+    RecipeNotFound(String),
+    UnableToReadRecipe(String, std::io::Error),
+    // The recipe's path, then what the parser said was wrong with it
+    InvalidRecipe(String, String),
+    UnableToReadDirectory(String, std::io::Error),
+    UnableToReadManifest(String, std::io::Error),
+    // The manifest's path, then what the parser said was wrong with it
+    InvalidManifest(String, String),
+    InvalidTemplate(String),
+    // A file in one folder of a batch with no file of the same name in the other
+    UnmatchedFile(String),
+    // A file in a batch folder, then its name which more than one image in one of the folders has
+    DuplicateName(String, String),
+    // Where more than one item of a batch would be saved, then the name of one of those items
+    DuplicateOutput(String, String),
+    // How many items of the batch failed, out of how many
+    BatchFailed(usize, usize),
+    UnableToReadImageFromPath(String, std::io::Error),
+    UnableToFormatImage(String),
+    UnableToDecodeImage(String, ImageError),
+    // The image's path and dimensions, then the limit it went over
+    ExceedsDecodeLimit(String, (u32, u32), Limit),
+    // A stream that went on past the allocation limit before it was even decoded, so there's no size to report
+    ExceedsReadLimit(String, Limit),
+    UnableToSaveImage(String, ImageError)
+}
+
+/// Broad kinds of error, each one exits with its own code so scripts can tell them apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// A bug, something that shouldn't be able to happen
+    Internal,
+    /// The arguments or settings given don't make sense
+    Usage,
+    /// One of the inputs couldn't be read
+    Input,
+    /// The output couldn't be written
+    Output,
+    /// Some of the items in a batch failed, the rest were saved
+    Batch
+}
+
+impl ErrorCategory {
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorCategory::Internal => "internal",
+            ErrorCategory::Usage => "usage",
+            ErrorCategory::Input => "input",
+            ErrorCategory::Output => "output",
+            ErrorCategory::Batch => "batch"
+        }
+    }
+
+    /// Code the process exits with, 0 is left for success
+    pub fn exit_code(self) -> u8 {
+        match self {
+            ErrorCategory::Internal => 1,
+            ErrorCategory::Usage => 2,
+            ErrorCategory::Input => 3,
+            ErrorCategory::Output => 4,
+            ErrorCategory::Batch => 5
+        }
+    }
+}
+
+impl ImageDataErrors {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            ImageDataErrors::BufferTooSmall => ErrorCategory::Internal,
+            ImageDataErrors::UnableToReadImageFromPath(..)
+            | ImageDataErrors::UnableToReadRecipe(..)
+            | ImageDataErrors::UnableToReadDirectory(..)
+            | ImageDataErrors::UnableToReadManifest(..)
+            | ImageDataErrors::UnmatchedFile(_)
+            | ImageDataErrors::DuplicateName(..)
+            | ImageDataErrors::UnableToFormatImage(_)
+            | ImageDataErrors::UnableToDecodeImage(..)
+            | ImageDataErrors::ExceedsDecodeLimit(..)
+            | ImageDataErrors::ExceedsReadLimit(..) => ErrorCategory::Input,
+            ImageDataErrors::UnableToSaveImage(..) | ImageDataErrors::DuplicateOutput(..) => ErrorCategory::Output,
+            ImageDataErrors::BatchFailed(..) => ErrorCategory::Batch,
+            _ => ErrorCategory::Usage
+        }
+    }
+
+    /// Path of the file the error is about, if it's about one
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            ImageDataErrors::UnableToReadImageFromPath(path, _)
+            | ImageDataErrors::UnableToFormatImage(path)
+            | ImageDataErrors::UnableToDecodeImage(path, _)
+            | ImageDataErrors::ExceedsDecodeLimit(path, ..)
+            | ImageDataErrors::ExceedsReadLimit(path, _)
+            | ImageDataErrors::UnableToSaveImage(path, _)
+            | ImageDataErrors::RecipeNotFound(path)
+            | ImageDataErrors::UnableToReadRecipe(path, _)
+            | ImageDataErrors::InvalidRecipe(path, _)
+            | ImageDataErrors::UnableToReadDirectory(path, _)
+            | ImageDataErrors::UnableToReadManifest(path, _)
+            | ImageDataErrors::InvalidManifest(path, _)
+            | ImageDataErrors::UnmatchedFile(path)
+            | ImageDataErrors::DuplicateName(path, _)
+            | ImageDataErrors::DuplicateOutput(path, _)
+            | ImageDataErrors::MissingOutputFormat(path) => Some(path),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for ImageDataErrors {
+    // What went wrong is written out here, why it went wrong (the io or image error) comes from source
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageDataErrors::BufferTooSmall => write!(f, "the combined pixels don't fit in the output image's buffer"),
+            ImageDataErrors::NotEnoughImages(count) => write!(f, "at least 2 images are needed to combine, got {}", count),
+            ImageDataErrors::UnknownCombiner(name) => write!(f, "unknown mode `{}`", name),
+            ImageDataErrors::InvalidPattern(value) => write!(f, "invalid pattern `{}`", value),
+            ImageDataErrors::InvalidWeights(weights) => write!(
+                f,
+                "invalid weights {:?}, there has to be one for each image, none of them negative and not all zero",
+                weights
+            ),
+            ImageDataErrors::InvalidSizePolicy(value) => write!(f, "invalid size, fit or anchor `{}`", value),
+            ImageDataErrors::InvalidFilter(value) => write!(f, "invalid filter or quality preset `{}`", value),
+            ImageDataErrors::InvalidEncoderOption(value) => write!(f, "invalid encoder option `{}`", value),
+            ImageDataErrors::InvalidMemoryBudget(value) => write!(f, "invalid memory budget `{}`, expected a size like 512M or 4G", value),
+            ImageDataErrors::OverMemoryBudget(needed, budget, reason) => write!(
+                f,
+                "combining needs about {} of memory, more than the budget of {}, and can't be done a strip at a time: {}",
+                format_size(*needed),
+                format_size(*budget),
+                reason
+            ),
+            ImageDataErrors::InvalidArguments(message) => write!(f, "{}", message),
+            ImageDataErrors::MissingOutput => write!(f, "no output path given, pass --output or set output in the recipe"),
+            ImageDataErrors::MissingOutputFormat(path) if path == crate::STDIO_PATH => {
+                write!(f, "no format given for standard output (`-`), pass --format as there's no extension to go by")
+            },
+            ImageDataErrors::MissingOutputFormat(path) => {
+                write!(f, "the extension of `{}` isn't a format that can be saved, pass --format or use one that is", path)
+            },
+            ImageDataErrors::RecipeNotFound(name) => write!(f, "no recipe file or saved recipe called `{}`", name),
+            ImageDataErrors::UnableToReadRecipe(path, _) => write!(f, "unable to read recipe `{}`", path),
+            ImageDataErrors::InvalidRecipe(path, message) => write!(f, "invalid recipe `{}`: {}", path, message),
+            ImageDataErrors::UnableToReadDirectory(path, _) => write!(f, "unable to read folder `{}`", path),
+            ImageDataErrors::UnableToReadManifest(path, _) => write!(f, "unable to read manifest `{}`", path),
+            ImageDataErrors::InvalidManifest(path, message) => write!(f, "invalid manifest `{}`: {}", path, message),
+            ImageDataErrors::InvalidTemplate(template) => write!(
+                f,
+                "invalid output template `{}`, the placeholders are {{name}}, {{index}}, {{left}} and {{right}} \
+                 and it needs {{name}} or {{index}} so the outputs don't save over each other",
+                template
+            ),
+            ImageDataErrors::UnmatchedFile(path) => write!(f, "`{}` has no file with the same name to pair with", path),
+            ImageDataErrors::DuplicateName(path, name) => {
+                write!(f, "`{}` can't be paired, there's more than one image named `{}` in one of the folders", path, name)
+            },
+            ImageDataErrors::DuplicateOutput(output, name) => {
+                write!(f, "`{}` is where more than one item would be saved, so `{}` wasn't combined", output, name)
+            },
+            ImageDataErrors::BatchFailed(failed, total) => write!(f, "{} of {} items in the batch failed", failed, total),
+            ImageDataErrors::UnableToReadImageFromPath(path, _) => write!(f, "unable to read `{}`", path),
+            ImageDataErrors::UnableToFormatImage(path) => write!(f, "unable to tell what format `{}` is in", path),
+            ImageDataErrors::UnableToDecodeImage(path, _) => write!(f, "unable to decode `{}`", path),
+            ImageDataErrors::ExceedsDecodeLimit(path, (width, height), limit) => {
+                write!(f, "`{}` is {}x{}, over {}", path, width, height, limit)
+            },
+            ImageDataErrors::ExceedsReadLimit(path, limit) => write!(f, "`{}` is too big to read into memory, over {}", path, limit),
+            // Images written straight to a writer don't have a path
+            ImageDataErrors::UnableToSaveImage(path, _) if path.is_empty() => write!(f, "unable to encode the image"),
+            ImageDataErrors::UnableToSaveImage(path, _) => write!(f, "unable to save `{}`", path)
+        }
+    }
+}
+
+impl Error for ImageDataErrors {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ImageDataErrors::UnableToReadImageFromPath(_, e)
+            | ImageDataErrors::UnableToReadRecipe(_, e)
+            | ImageDataErrors::UnableToReadDirectory(_, e)
+            | ImageDataErrors::UnableToReadManifest(_, e) => Some(e),
+            ImageDataErrors::UnableToDecodeImage(_, e) | ImageDataErrors::UnableToSaveImage(_, e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+/// Something worth telling whoever's running it about, that doesn't stop the image being made.
+/// The library never prints these itself, see [`crate::Combine::on_warning`] and [`crate::format_mismatch`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    /// The file's path, the format its extension claims, then the format it really is
+    MisnamedFormat(String, ImageFormat, ImageFormat),
+    /// The output format can't store alpha, so see through pixels were flattened onto the background
+    TransparencyFlattened(ImageFormat, Background),
+    /// Weights were given, but only alternating with a noise pattern uses them
+    WeightsIgnored
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::MisnamedFormat(path, claimed, format) => write!(
+                f,
+                "`{}` is named like a {} but its contents are {}, it's being read as {}",
+                path,
+                format_name(*claimed),
+                format_name(*format),
+                format_name(*format)
+            ),
+            Warning::TransparencyFlattened(format, background) => write!(
+                f,
+                "{} can't store transparency, see through pixels are laid on top of the background ({})",
+                format_name(*format),
+                background
+            ),
+            Warning::WeightsIgnored => write!(
+                f,
+                "weights only change how alternate shares out the noise patterns (random, bayer and blue-noise), they're being ignored"
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn io_error() -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "no such file")
+    }
+
+    #[test]
+    fn exit_codes_stay_the_same() {
+        // Scripts check these, so changing one is a breaking change
+        let categories = [
+            (ErrorCategory::Internal, "internal", 1),
+            (ErrorCategory::Usage, "usage", 2),
+            (ErrorCategory::Input, "input", 3),
+            (ErrorCategory::Output, "output", 4),
+            (ErrorCategory::Batch, "batch", 5)
+        ];
+        for (category, name, code) in categories {
+            assert_eq!((category.name(), category.exit_code()), (name, code));
+        }
+    }
+
+    #[test]
+    fn errors_fall_into_the_right_category() {
+        let decode = ImageError::IoError(io_error());
+        let errors = [
+            (ImageDataErrors::BufferTooSmall, ErrorCategory::Internal),
+            (ImageDataErrors::UnknownCombiner("x".to_string()), ErrorCategory::Usage),
+            (ImageDataErrors::InvalidWeights(vec![-1.0]), ErrorCategory::Usage),
+            (ImageDataErrors::MissingOutputFormat("-".to_string()), ErrorCategory::Usage),
+            (ImageDataErrors::RecipeNotFound("r".to_string()), ErrorCategory::Usage),
+            (ImageDataErrors::UnableToReadImageFromPath("a.png".to_string(), io_error()), ErrorCategory::Input),
+            (ImageDataErrors::UnableToDecodeImage("a.png".to_string(), decode), ErrorCategory::Input),
+            (ImageDataErrors::ExceedsDecodeLimit("a.png".to_string(), (9, 9), Limit::Width(8)), ErrorCategory::Input),
+            (ImageDataErrors::UnmatchedFile("a.png".to_string()), ErrorCategory::Input),
+            (ImageDataErrors::UnableToSaveImage("out.png".to_string(), ImageError::IoError(io_error())), ErrorCategory::Output),
+            (ImageDataErrors::DuplicateOutput("out.png".to_string(), "a".to_string()), ErrorCategory::Output),
+            (ImageDataErrors::BatchFailed(1, 2), ErrorCategory::Batch)
+        ];
+        for (error, category) in errors {
+            assert_eq!(error.category(), category, "{}", error);
+        }
+    }
+
+    #[test]
+    fn paths_and_sources_come_with_the_errors_about_files() {
+        let error = ImageDataErrors::UnableToReadImageFromPath("a.png".to_string(), io_error());
+        assert_eq!(error.path(), Some("a.png"));
+        assert_eq!(error.to_string(), "unable to read `a.png`");
+        assert_eq!(error.source().map(|source| source.to_string()).as_deref(), Some("no such file"));
+
+        let error = ImageDataErrors::ExceedsDecodeLimit("big.png".to_string(), (20_000, 10), Limit::Width(10_000));
+        assert_eq!(error.path(), Some("big.png"));
+        assert_eq!(error.to_string(), "`big.png` is 20000x10, over the limit of 10000 pixels wide");
+        assert!(error.source().is_none());
+
+        let error = ImageDataErrors::NotEnoughImages(1);
+        assert_eq!(error.path(), None);
+        assert_eq!(error.to_string(), "at least 2 images are needed to combine, got 1");
+    }
+}
@@ -0,0 +1,354 @@
+use crate::ImageDataErrors;
+use image::{ColorType, DynamicImage};
+use std::borrow::Cow;
+use std::convert::TryInto;
+
+/// How precisely each channel of a pixel is stored, from least to most precise
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Precision {
+    U8,
+    U16,
+    F32
+}
+
+impl Precision {
+    /// The precision needed to keep all the detail of the given images,
+    /// e.g. a single 16-bit PNG means everything gets combined in 16 bits
+    pub fn of(images: &[DynamicImage]) -> Self {
+        images.iter().map(|image| Precision::of_color(image.color())).max().unwrap_or(Precision::U8)
+    }
+
+    /// The precision needed for pixels of the colour type given
+    pub fn of_color(color: ColorType) -> Self {
+        match color {
+            ColorType::Rgb32F | ColorType::Rgba32F => Precision::F32,
+            ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16 => Precision::U16,
+            _ => Precision::U8
+        }
+    }
+
+    /// Bytes taken up by one sample at this precision
+    pub fn sample_size(self) -> u64 {
+        match self {
+            Precision::U8 => 1,
+            Precision::U16 => 2,
+            Precision::F32 => 4
+        }
+    }
+}
+
+/// Which channels the pixels need, the same as the image crate's colour types but without the sample type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channels {
+    L,
+    La,
+    Rgb,
+    Rgba
+}
+
+impl Channels {
+    /// Channels for pixels that do or don't have colour and alpha
+    pub fn new(color: bool, alpha: bool) -> Self {
+        match (color, alpha) {
+            (false, false) => Channels::L,
+            (false, true) => Channels::La,
+            (true, false) => Channels::Rgb,
+            (true, true) => Channels::Rgba
+        }
+    }
+
+    /// The richest channels among the given images, e.g. a greyscale scan and one with
+    /// transparency need La, anything in colour needs Rgb. Palette images are already
+    /// expanded to Rgb(a) by the decoders
+    pub fn of(images: &[DynamicImage]) -> Self {
+        let color = images.iter().any(|image| image.color().has_color());
+        let alpha = images.iter().any(|image| image.color().has_alpha());
+        Channels::new(color, alpha)
+    }
+
+    pub fn has_color(self) -> bool {
+        matches!(self, Channels::Rgb | Channels::Rgba)
+    }
+
+    pub fn has_alpha(self) -> bool {
+        matches!(self, Channels::La | Channels::Rgba)
+    }
+
+    /// Number of samples in each pixel
+    pub fn count(self) -> u8 {
+        match self {
+            Channels::L => 1,
+            Channels::La => 2,
+            Channels::Rgb => 3,
+            Channels::Rgba => 4
+        }
+    }
+
+    /// Which of the rgba samples are kept for these channels
+    pub fn indices(self) -> &'static [usize] {
+        match self {
+            Channels::L => &[0],
+            Channels::La => &[0, 3],
+            Channels::Rgb => &[0, 1, 2],
+            Channels::Rgba => &[0, 1, 2, 3]
+        }
+    }
+}
+
+/// A type one channel of a pixel can be stored as: u8, u16 or f32
+// Send and Sync so rows of samples can be worked on from other threads
+pub trait Sample: bytemuck::Pod + PartialEq + Default + std::fmt::Debug + Send + Sync {
+    const PRECISION: Precision;
+    /// Value of a fully opaque alpha
+    const OPAQUE: Self;
+
+    /// Converts to a value from 0.0 - 1.0
+    fn to_unit(self) -> f32;
+
+    /// Converts back from a value from 0.0 - 1.0. Whole number types clamp and round,
+    /// f32 keeps the value as it is so brighter than white HDR values survive
+    fn from_unit(value: f32) -> Self;
+
+    /// The rgba pixels of an image at this precision. Borrowed straight out of the image
+    /// when it's already rgba of this type, so only other layouts get copied
+    fn rgba(image: &DynamicImage) -> Cow<'_, [Self]>;
+
+    /// The image crate's colour type for pixels with this many channels of this type, if it has one
+    fn color_type(channels: u8) -> Option<ColorType>;
+}
+
+impl Sample for u8 {
+    const PRECISION: Precision = Precision::U8;
+    const OPAQUE: Self = u8::MAX;
+
+    fn to_unit(self) -> f32 {
+        self as f32 / 255.0
+    }
+
+    fn from_unit(value: f32) -> Self {
+        (value.clamp(0.0, 1.0) * 255.0).round() as u8
+    }
+
+    fn rgba(image: &DynamicImage) -> Cow<'_, [Self]> {
+        match image {
+            DynamicImage::ImageRgba8(buffer) => Cow::Borrowed(buffer.as_raw()),
+            _ => Cow::Owned(image.to_rgba8().into_raw())
+        }
+    }
+
+    fn color_type(channels: u8) -> Option<ColorType> {
+        match channels {
+            1 => Some(ColorType::L8),
+            2 => Some(ColorType::La8),
+            3 => Some(ColorType::Rgb8),
+            4 => Some(ColorType::Rgba8),
+            _ => None
+        }
+    }
+}
+
+impl Sample for u16 {
+    const PRECISION: Precision = Precision::U16;
+    const OPAQUE: Self = u16::MAX;
+
+    fn to_unit(self) -> f32 {
+        self as f32 / 65535.0
+    }
+
+    fn from_unit(value: f32) -> Self {
+        (value.clamp(0.0, 1.0) * 65535.0).round() as u16
+    }
+
+    fn rgba(image: &DynamicImage) -> Cow<'_, [Self]> {
+        match image {
+            DynamicImage::ImageRgba16(buffer) => Cow::Borrowed(buffer.as_raw()),
+            _ => Cow::Owned(image.to_rgba16().into_raw())
+        }
+    }
+
+    fn color_type(channels: u8) -> Option<ColorType> {
+        match channels {
+            1 => Some(ColorType::L16),
+            2 => Some(ColorType::La16),
+            3 => Some(ColorType::Rgb16),
+            4 => Some(ColorType::Rgba16),
+            _ => None
+        }
+    }
+}
+
+impl Sample for f32 {
+    const PRECISION: Precision = Precision::F32;
+    const OPAQUE: Self = 1.0;
+
+    fn to_unit(self) -> f32 {
+        self
+    }
+
+    fn from_unit(value: f32) -> Self {
+        value
+    }
+
+    fn rgba(image: &DynamicImage) -> Cow<'_, [Self]> {
+        match image {
+            DynamicImage::ImageRgba32F(buffer) => Cow::Borrowed(buffer.as_raw()),
+            _ => Cow::Owned(image.to_rgba32f().into_raw())
+        }
+    }
+
+    // The image crate has no greyscale floating point colour types
+    fn color_type(channels: u8) -> Option<ColorType> {
+        match channels {
+            3 => Some(ColorType::Rgb32F),
+            4 => Some(ColorType::Rgba32F),
+            _ => None
+        }
+    }
+}
+
+/// Converts samples from one type to another, going through 0.0 - 1.0.
+/// When both types are the same the samples are borrowed as they are
+pub fn convert_samples<S: Sample, T: Sample>(data: &[S]) -> Cow<'_, [T]> {
+    if S::PRECISION == T::PRECISION {
+        Cow::Borrowed(bytemuck::cast_slice(data))
+    } else {
+        Cow::Owned(data.iter().map(|sample| T::from_unit(sample.to_unit())).collect())
+    }
+}
+
+/// Acts as a temporary storage for Image meta data before being saved
+pub struct FloatingImage<T: Sample = u8> {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<T>, // Data will reserve memory for the pixel rgba values, T is u8 for 0 - 255, u16 for 16-bit images or f32 for HDR
+    pub name: String,
+    // Data is always rgba while combining, this is what's actually needed when saving
+    pub channels: Channels
+}
+
+impl<T: Sample> FloatingImage<T> {
+    pub fn new(width: u32, height: u32, name: String) -> Self {
+        let buffer_capacity = height as u64 * width as u64 * 4; // 4: number of pixels' rgba values
+        let buffer = Vec::with_capacity(buffer_capacity.try_into().unwrap());
+
+        FloatingImage {
+            width,
+            height,
+            data: buffer,
+            name,
+            channels: Channels::Rgba
+        }
+    }
+    // Methods on a struct take in self as first argument
+
+    pub fn set_data(&mut self, data: Vec<T>) -> Result<(), ImageDataErrors> {
+        // If the data passed in is bigger than the capacity, means buffer is not big enough to hold onto input data
+        if data.len() > self.data.capacity() {
+            return Err(ImageDataErrors::BufferTooSmall)
+        }
+        self.data = data;
+        Ok(())
+    }
+}
+
+/// A FloatingImage at whichever precision the combiner ran in
+pub enum DynamicFloatingImage {
+    U8(FloatingImage<u8>),
+    U16(FloatingImage<u16>),
+    F32(FloatingImage<f32>)
+}
+
+impl<T: Sample> FloatingImage<T> {
+    /// Copies the pixels of a decoded image, converted to samples of type T
+    pub fn from_image(image: &DynamicImage, name: String) -> Self {
+        FloatingImage {
+            width: image.width(),
+            height: image.height(),
+            data: T::rgba(image).into_owned(),
+            name,
+            channels: Channels::Rgba
+        }
+    }
+
+    /// Wraps the image up with its precision
+    pub fn into_dynamic(self) -> DynamicFloatingImage {
+        // Only one of these casts can succeed, the one matching T
+        let FloatingImage { width, height, data, name, channels } = self;
+        match T::PRECISION {
+            Precision::U8 => DynamicFloatingImage::U8(FloatingImage { width, height, data: bytemuck::cast_vec(data), name, channels }),
+            Precision::U16 => DynamicFloatingImage::U16(FloatingImage { width, height, data: bytemuck::cast_vec(data), name, channels }),
+            Precision::F32 => DynamicFloatingImage::F32(FloatingImage { width, height, data: bytemuck::cast_vec(data), name, channels })
+        }
+    }
+}
+
+impl DynamicFloatingImage {
+    /// Copies a decoded image at the precision given, it gets saved with the channels given
+    /// (or more, if the pixels need them)
+    pub fn from_image(image: &DynamicImage, precision: Precision, channels: Channels, name: String) -> Self {
+        let mut output = match precision {
+            Precision::U8 => FloatingImage::<u8>::from_image(image, name).into_dynamic(),
+            Precision::U16 => FloatingImage::<u16>::from_image(image, name).into_dynamic(),
+            Precision::F32 => FloatingImage::<f32>::from_image(image, name).into_dynamic()
+        };
+        output.set_channels(channels);
+        output
+    }
+
+    pub fn precision(&self) -> Precision {
+        match self {
+            DynamicFloatingImage::U8(_) => Precision::U8,
+            DynamicFloatingImage::U16(_) => Precision::U16,
+            DynamicFloatingImage::F32(_) => Precision::F32
+        }
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            DynamicFloatingImage::U8(image) => (image.width, image.height),
+            DynamicFloatingImage::U16(image) => (image.width, image.height),
+            DynamicFloatingImage::F32(image) => (image.width, image.height)
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            DynamicFloatingImage::U8(image) => &image.name,
+            DynamicFloatingImage::U16(image) => &image.name,
+            DynamicFloatingImage::F32(image) => &image.name
+        }
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        match self {
+            DynamicFloatingImage::U8(image) => image.name = name,
+            DynamicFloatingImage::U16(image) => image.name = name,
+            DynamicFloatingImage::F32(image) => image.name = name
+        }
+    }
+
+    pub fn channels(&self) -> Channels {
+        match self {
+            DynamicFloatingImage::U8(image) => image.channels,
+            DynamicFloatingImage::U16(image) => image.channels,
+            DynamicFloatingImage::F32(image) => image.channels
+        }
+    }
+
+    pub fn set_channels(&mut self, channels: Channels) {
+        match self {
+            DynamicFloatingImage::U8(image) => image.channels = channels,
+            DynamicFloatingImage::U16(image) => image.channels = channels,
+            DynamicFloatingImage::F32(image) => image.channels = channels
+        }
+    }
+
+    /// The rgba samples converted to type T, borrowed when they already are T
+    pub fn samples<T: Sample>(&self) -> Cow<'_, [T]> {
+        match self {
+            DynamicFloatingImage::U8(image) => convert_samples(&image.data),
+            DynamicFloatingImage::U16(image) => convert_samples(&image.data),
+            DynamicFloatingImage::F32(image) => convert_samples(&image.data)
+        }
+    }
+}
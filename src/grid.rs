@@ -0,0 +1,98 @@
+use image::{imageops, DynamicImage, Rgba, Rgba32FImage};
+
+/// Lays the images out left to right, top to bottom, in rows of the given number of columns.
+/// The images need to be the same size already, gap is the space between them in pixels
+/// and gets filled with the background
+pub fn grid(images: &[DynamicImage], columns: u32, gap: u32, background: Rgba<u8>) -> DynamicImage {
+    let count = images.len() as u32;
+    let columns = columns.clamp(1, count.max(1));
+    let rows = count.div_ceil(columns);
+    let (cell_width, cell_height) = images.first().map(|image| (image.width(), image.height())).unwrap_or((0, 0));
+
+    let width = columns * cell_width + (columns - 1) * gap;
+    let height = rows * cell_height + rows.saturating_sub(1) * gap;
+
+    // Floating point so 16-bit and HDR images keep their precision, the same as padding does
+    let mut canvas = Rgba32FImage::from_pixel(width, height, Rgba(background.0.map(|channel| channel as f32 / 255.0)));
+    for (index, image) in images.iter().enumerate() {
+        let (column, row) = (index as u32 % columns, index as u32 / columns);
+        let x = column * (cell_width + gap);
+        let y = row * (cell_height + gap);
+        imageops::replace(&mut canvas, &image.to_rgba32f(), x as i64, y as i64);
+    }
+
+    DynamicImage::ImageRgba32F(canvas)
+}
+
+/// Cuts the image into a grid of tiles, returned row by row. When the image doesn't divide evenly
+/// the tiles differ by a pixel at most, rather than the last row or column being cut short
+pub fn split(image: &DynamicImage, columns: u32, rows: u32) -> Vec<DynamicImage> {
+    let columns = columns.clamp(1, image.width().max(1));
+    let rows = rows.clamp(1, image.height().max(1));
+    // Edge of the nth tile along a side of the given length, u64 so it can't overflow
+    let edge = |n: u32, count: u32, length: u32| (n as u64 * length as u64 / count as u64) as u32;
+
+    let mut tiles = Vec::with_capacity((columns * rows) as usize);
+    for row in 0..rows {
+        let (top, bottom) = (edge(row, rows, image.height()), edge(row + 1, rows, image.height()));
+        for column in 0..columns {
+            let (left, right) = (edge(column, columns, image.width()), edge(column + 1, columns, image.width()));
+            tiles.push(image.crop_imm(left, top, right - left, bottom - top));
+        }
+    }
+    tiles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbaImage;
+
+    fn filled(width: u32, height: u32, colour: [u8; 4]) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, Rgba(colour)))
+    }
+
+    #[test]
+    fn grid_leaves_gaps_between_the_cells() {
+        let (red, green, blue) = ([255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]);
+        let white = Rgba([255, 255, 255, 255]);
+        let images = [filled(2, 1, red), filled(2, 1, green), filled(2, 1, blue)];
+        let result = grid(&images, 2, 1, white).to_rgba8();
+
+        // Two columns of 2 with a gap of 1, two rows of 1 with a gap of 1, the last cell is empty
+        assert_eq!(result.dimensions(), (5, 3));
+        let rows = [
+            [red, red, white.0, green, green],
+            [white.0; 5],
+            [blue, blue, white.0, white.0, white.0]
+        ];
+        for (y, row) in rows.iter().enumerate() {
+            for (x, colour) in row.iter().enumerate() {
+                assert_eq!(result.get_pixel(x as u32, y as u32).0, *colour, "({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn grid_columns_are_clamped_to_the_images() {
+        let images = [filled(3, 2, [0; 4]), filled(3, 2, [0; 4])];
+        assert_eq!(grid(&images, 5, 0, Rgba([0; 4])).width(), 6);
+        assert_eq!(grid(&images, 0, 0, Rgba([0; 4])).height(), 4);
+    }
+
+    #[test]
+    fn split_spreads_the_extra_pixels_out() {
+        // Each pixel's red is its x and green its y, so the tiles can be checked for where they came from
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(5, 3, |x, y| Rgba([x as u8, y as u8, 0, 255])));
+        let tiles = split(&image, 2, 2);
+
+        let sizes: Vec<_> = tiles.iter().map(|tile| (tile.width(), tile.height())).collect();
+        assert_eq!(sizes, [(2, 1), (3, 1), (2, 2), (3, 2)]);
+        let corners: Vec<_> = tiles.iter().map(|tile| tile.to_rgba8().get_pixel(0, 0).0[..2].to_vec()).collect();
+        assert_eq!(corners, [[0, 0], [2, 0], [0, 1], [2, 1]]);
+
+        // Splitting back up then laying the tiles out again gives the same image, when they're even
+        let even = DynamicImage::ImageRgba8(RgbaImage::from_fn(4, 2, |x, y| Rgba([x as u8, y as u8, 0, 255])));
+        assert_eq!(grid(&split(&even, 2, 2), 2, 0, Rgba([0; 4])).to_rgba8(), even.to_rgba8());
+    }
+}
@@ -0,0 +1,261 @@
+//! Combines two or more images into one, by alternating their pixels in a pattern,
+//! blending them (multiply, screen, overlay...) or compositing them (Porter-Duff over, in, out...).
+//!
+//! The [`Combine`] builder is the easiest way in, it takes care of decoding, resizing and saving:
+//!
+//! ```no_run
+//! use image_combiner::Combine;
+//! use image_combiner::size::SizePolicy;
+//!
+//! # fn main() -> Result<(), image_combiner::ImageDataErrors> {
+//! let image = Combine::new()
+//!     .input("left.png")
+//!     .input("right.png")
+//!     .mode("multiply")
+//!     .size_policy(SizePolicy::default())
+//!     .run()?;
+//!
+//! // Or straight into anything that implements Write, e.g. a response body
+//! let mut body = Vec::new();
+//! Combine::new()
+//!     .inputs(["a.jpg", "b.jpg"])
+//!     .write_to(&mut body, image::ImageFormat::Png)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Everything the builder uses is public too, for anyone that needs to do things differently
+//! e.g. registering their own [`combiner::Combiner`]
+
+pub mod batch;
+pub mod blend;
+pub mod combine;
+pub mod combiner;
+pub mod composite;
+pub mod diff;
+pub mod error;
+pub mod floating_image;
+pub mod grid;
+pub mod limits;
+pub mod output;
+pub mod pattern;
+pub mod resample;
+pub mod size;
+pub mod tiled;
+
+pub use combine::Combine;
+pub use error::{ErrorCategory, ImageDataErrors, Warning};
+pub use floating_image::{DynamicFloatingImage, FloatingImage};
+
+use image::{io::Reader, ImageError, ImageFormat, DynamicImage};
+use limits::{DecodeLimits, Limit};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, Read, Seek};
+use std::path::Path;
+
+/// The path that means standard input when reading an image and standard output when saving one,
+/// so images can be piped in and out e.g. `curl ... | image-combiner combine - b.png -o - --format png > out.png`
+pub const STDIO_PATH: &str = "-";
+
+/// Takes in path as a string, returns the DynamicImage from image crate along with its format.
+/// Decoding is held to the default limits, see find_image_from_path_with_limits
+pub fn find_image_from_path(path: String) -> Result<(DynamicImage, ImageFormat), ImageDataErrors> {
+    find_image_from_path_with_limits(path, &DecodeLimits::default())
+}
+
+/// The same as find_image_from_path, but images over the limits given fail with
+/// [`ImageDataErrors::ExceedsDecodeLimit`] before their pixels are decoded.
+/// A path of `-` reads the image from standard input
+pub fn find_image_from_path_with_limits(path: String, limits: &DecodeLimits) -> Result<(DynamicImage, ImageFormat), ImageDataErrors> {
+    if path == STDIO_PATH {
+        return find_image_from_reader(std::io::stdin().lock(), path, limits);
+    }
+
+    let image_reader = open_image(&path)?;
+    decode(image_reader, path, limits)
+}
+
+/// Reads a whole image out of reader, e.g. standard input or a network stream, and decodes it.
+/// Streams don't have an extension, so the format can only come from the bytes themselves.
+/// One that goes on past the allocation limit fails with [`ImageDataErrors::ExceedsReadLimit`].
+/// name is what the image is called in errors
+pub fn find_image_from_reader<R: Read>(mut reader: R, name: String, limits: &DecodeLimits) -> Result<(DynamicImage, ImageFormat), ImageDataErrors> {
+    // Decoders need to jump around, which most streams can't do, so it all gets read into memory first.
+    // That's held to the allocation limit too, reading one byte past it is how going over gets noticed
+    let mut bytes = Vec::new();
+    let read = match limits.max_alloc {
+        Some(max_alloc) => reader.take(max_alloc.saturating_add(1)).read_to_end(&mut bytes),
+        None => reader.read_to_end(&mut bytes)
+    };
+    if let Err(e) = read {
+        return Err(ImageDataErrors::UnableToReadImageFromPath(name, e));
+    }
+    if let Some(max_alloc) = limits.max_alloc.filter(|&max_alloc| bytes.len() as u64 > max_alloc) {
+        return Err(ImageDataErrors::ExceedsReadLimit(name, Limit::Allocation(max_alloc)));
+    }
+
+    match Reader::new(Cursor::new(bytes)).with_guessed_format() {
+        Ok(image_reader) => decode(image_reader, name, limits),
+        Err(e) => Err(ImageDataErrors::UnableToReadImageFromPath(name, e))
+    }
+}
+
+/// Decodes the image from a reader that knows its format, held to the limits
+fn decode<R: BufRead + Seek>(image_reader: Reader<R>, path: String, limits: &DecodeLimits) -> Result<(DynamicImage, ImageFormat), ImageDataErrors> {
+    // Get's the image format from the image reader
+    // if let Some() : when dealing with Option
+    let Some(image_format) = image_reader.format() else {
+        return Err(ImageDataErrors::UnableToFormatImage(path));
+    };
+
+    // Reading the dimensions only looks at the header, then it's back to the start for decoding
+    let mut inner = image_reader.into_inner();
+    let dimensions = match Reader::with_format(&mut inner, image_format).into_dimensions() {
+        Ok(dimensions) => dimensions,
+        Err(e) => return Err(ImageDataErrors::UnableToDecodeImage(path, e))
+    };
+    if let Err(limit) = limits.check(dimensions.0, dimensions.1) {
+        return Err(ImageDataErrors::ExceedsDecodeLimit(path, dimensions, limit));
+    }
+    if let Err(e) = inner.rewind() {
+        return Err(ImageDataErrors::UnableToReadImageFromPath(path, e));
+    }
+
+    let mut image_reader = Reader::with_format(inner, image_format);
+    image_reader.limits(limits.image_limits());
+    match image_reader.decode() {
+        // Return both values in a tuple (image and it's format)
+        Ok(image) => Ok((image, image_format)),
+        // The size was fine, so it's the decoder needing more memory than it's allowed
+        Err(ImageError::Limits(e)) => match limits.max_alloc {
+            Some(max_alloc) => Err(ImageDataErrors::ExceedsDecodeLimit(path, dimensions, Limit::Allocation(max_alloc))),
+            None => Err(ImageDataErrors::UnableToDecodeImage(path, ImageError::Limits(e)))
+        },
+        Err(e) => Err(ImageDataErrors::UnableToDecodeImage(path, e))
+    }
+}
+
+/// Standard input can only be read once, so `-` can't be more than one of the inputs
+pub fn check_stdin_once<'a>(paths: impl IntoIterator<Item = &'a str>) -> Result<(), ImageDataErrors> {
+    if paths.into_iter().filter(|path| *path == STDIO_PATH).count() > 1 {
+        return Err(ImageDataErrors::InvalidArguments("standard input (`-`) can only be read once, so it can only be one of the images".to_string()));
+    }
+    Ok(())
+}
+
+/// Opens the image at path, working out its format from the first few bytes of the file so a PNG
+/// saved as .jpg still opens. The extension is only used when the bytes don't match any format
+pub fn open_image(path: &str) -> Result<Reader<BufReader<File>>, ImageDataErrors> {
+    // Reader struct implements an open function which takes a path to an image file,
+    // it starts off with the format the extension says
+    Reader::open(path)
+        .and_then(|image_reader| image_reader.with_guessed_format())
+        .map_err(|e| ImageDataErrors::UnableToReadImageFromPath(path.to_string(), e))
+}
+
+/// The warning to give when the file's extension says it's a different format to what it really is,
+/// e.g. after decoding it with find_image_from_path. Nothing is printed, that's up to the caller
+pub fn format_mismatch(path: &str, format: ImageFormat) -> Option<Warning> {
+    let extension = Path::new(path).extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+    match ImageFormat::from_extension(extension) {
+        Some(claimed) if claimed != format => Some(Warning::MisnamedFormat(path.to_string(), claimed, format)),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CRC-32 the way PNG chunks use it, so the forged header still reads as valid
+    fn crc32(bytes: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for &byte in bytes {
+            crc ^= byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+            }
+        }
+        !crc
+    }
+
+    /// A real 1x1 PNG with its header changed to claim it's width by height, the pixels are never
+    /// got to so it doesn't matter that there's nowhere near enough of them
+    fn forged_png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DynamicImage::new_rgb8(1, 1).write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png).unwrap();
+        // 8 byte signature, 4 byte length, then the IHDR chunk's type and data the CRC covers
+        bytes[16..20].copy_from_slice(&width.to_be_bytes());
+        bytes[20..24].copy_from_slice(&height.to_be_bytes());
+        let crc = crc32(&bytes[12..29]);
+        bytes[29..33].copy_from_slice(&crc.to_be_bytes());
+        bytes
+    }
+
+    fn decode_forged(width: u32, height: u32, limits: DecodeLimits) -> Result<(DynamicImage, ImageFormat), ImageDataErrors> {
+        find_image_from_reader(Cursor::new(forged_png(width, height)), "forged.png".to_string(), &limits)
+    }
+
+    #[test]
+    fn forged_headers_are_stopped_before_decoding() {
+        let none = DecodeLimits::none();
+        let exceeds = |result, expected: Limit| match result {
+            Err(ImageDataErrors::ExceedsDecodeLimit(_, _, limit)) => assert_eq!(limit, expected),
+            Err(e) => panic!("expected {:?}, got {}", expected, e),
+            Ok(_) => panic!("expected {:?}, it decoded", expected)
+        };
+
+        exceeds(decode_forged(100_000, 1, DecodeLimits { max_width: Some(50_000), ..none }), Limit::Width(50_000));
+        exceeds(decode_forged(1, 100_000, DecodeLimits { max_height: Some(50_000), ..none }), Limit::Height(50_000));
+        // Within both sides, but not the two together
+        exceeds(decode_forged(40_000, 40_000, DecodeLimits { max_pixels: Some(1_000_000_000), ..none }), Limit::Pixels(1_000_000_000));
+        // Fine by the size limits, but the buffer for it would be too big
+        exceeds(decode_forged(40_000, 40_000, DecodeLimits { max_alloc: Some(1024), ..none }), Limit::Allocation(1024));
+
+        // The height limit is fine with a wide image, it's the buffer that stops it, reported with the header's size
+        match decode_forged(100_000, 1, DecodeLimits { max_height: Some(50_000), max_alloc: Some(1024), ..none }) {
+            Err(ImageDataErrors::ExceedsDecodeLimit(path, dimensions, Limit::Allocation(_))) => {
+                assert_eq!((path.as_str(), dimensions), ("forged.png", (100_000, 1)))
+            },
+            other => panic!("expected the allocation limit, got {:?}", other.err())
+        }
+        // The real image it was forged from is fine under all of them
+        assert!(decode_forged(1, 1, DecodeLimits { max_width: Some(1), max_height: Some(1), max_pixels: Some(1), max_alloc: Some(1024) }).is_ok());
+    }
+
+    #[test]
+    fn streams_stop_at_the_allocation_limit() {
+        let limits = DecodeLimits { max_alloc: Some(16), ..DecodeLimits::default() };
+        let result = find_image_from_reader(Cursor::new(vec![0; 100]), STDIO_PATH.to_string(), &limits);
+        assert!(matches!(result, Err(ImageDataErrors::ExceedsReadLimit(_, Limit::Allocation(16)))));
+
+        // Right on the limit is still read, it's only the format that's wrong with it
+        let result = find_image_from_reader(Cursor::new(vec![0; 16]), STDIO_PATH.to_string(), &limits);
+        assert!(matches!(result, Err(ImageDataErrors::UnableToFormatImage(_))));
+    }
+
+    #[test]
+    fn misnamed_images_are_read_by_their_contents() {
+        let path = std::env::temp_dir().join(format!("image-combiner-{}-misnamed.jpg", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let original = DynamicImage::ImageRgb8(image::RgbImage::from_fn(5, 3, |x, y| image::Rgb([x as u8 * 50, y as u8 * 80, 7])));
+        original.save_with_format(&path, ImageFormat::Png).unwrap();
+
+        let decoded = find_image_from_path(path.clone());
+        let _ = std::fs::remove_file(&path);
+        let (image, format) = decoded.unwrap();
+
+        // Lossless, so it really was read as a PNG and not guessed at as a JPEG
+        assert_eq!(format, ImageFormat::Png);
+        assert_eq!(image, original);
+
+        let warning = format_mismatch(&path, format).unwrap();
+        assert_eq!(warning, Warning::MisnamedFormat(path.clone(), ImageFormat::Jpeg, ImageFormat::Png));
+        assert_eq!(warning.to_string(), format!("`{}` is named like a JPEG but its contents are PNG, it's being read as PNG", path));
+
+        // Nothing to say when the name is right, or when there's no extension to go by
+        assert_eq!(format_mismatch("photo.png", ImageFormat::Png), None);
+        assert_eq!(format_mismatch("photo.PNG", ImageFormat::Png), None);
+        assert_eq!(format_mismatch(STDIO_PATH, ImageFormat::Png), None);
+    }
+}
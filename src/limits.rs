@@ -0,0 +1,69 @@
+use crate::tiled::format_size;
+use std::fmt;
+
+/// Limits on the images that get decoded. The size is checked against each image's header before
+/// any of its pixels are read, so a small file claiming to be enormous (a decompression bomb)
+/// fails straight away instead of eating all of the memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    // Width times height, for images that are within both of the other limits but still too big
+    pub max_pixels: Option<u64>,
+    // Most bytes the decoder can allocate for a single image
+    pub max_alloc: Option<u64>
+}
+
+impl Default for DecodeLimits {
+    // The same as the image crate's defaults, which is what decoding has always been limited by
+    fn default() -> Self {
+        DecodeLimits { max_width: None, max_height: None, max_pixels: None, max_alloc: Some(512 * 1024 * 1024) }
+    }
+}
+
+/// Which of the limits an image went over, with what the limit was
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Width(u32),
+    Height(u32),
+    Pixels(u64),
+    Allocation(u64)
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Width(width) => write!(f, "the limit of {} pixels wide", width),
+            Limit::Height(height) => write!(f, "the limit of {} pixels tall", height),
+            Limit::Pixels(pixels) => write!(f, "the limit of {} pixels", pixels),
+            Limit::Allocation(bytes) => write!(f, "the limit of {} of memory to decode it", format_size(*bytes))
+        }
+    }
+}
+
+impl DecodeLimits {
+    /// No limits at all, only for images that are trusted
+    pub fn none() -> Self {
+        DecodeLimits { max_width: None, max_height: None, max_pixels: None, max_alloc: None }
+    }
+
+    /// Checks an image's size against the limits, returning the first one it's over
+    pub fn check(&self, width: u32, height: u32) -> Result<(), Limit> {
+        match (self.max_width, self.max_height, self.max_pixels) {
+            (Some(max), _, _) if width > max => Err(Limit::Width(max)),
+            (_, Some(max), _) if height > max => Err(Limit::Height(max)),
+            (_, _, Some(max)) if width as u64 * height as u64 > max => Err(Limit::Pixels(max)),
+            _ => Ok(())
+        }
+    }
+
+    /// The limits for the image crate's decoders. Only the allocation limit is needed, the size has
+    /// already been checked by then, but the width and height go in too so they're never skipped
+    pub(crate) fn image_limits(&self) -> image::io::Limits {
+        let mut limits = image::io::Limits::no_limits();
+        limits.max_image_width = self.max_width;
+        limits.max_image_height = self.max_height;
+        limits.max_alloc = self.max_alloc;
+        limits
+    }
+}
@@ -1,183 +1,366 @@
 mod args;
+mod recipe;
+
+use args::{Args, BatchArgs, Cli, Command, CombineArgs, DiffArgs, ErrorFormat, GridArgs, InfoArgs, SplitArgs};
+use clap::{CommandFactory, Parser};
+use image::{DynamicImage, ImageFormat};
+use image_combiner::batch::{self, NameTemplate};
+use image_combiner::combiner::{CombineOptions, Registry};
+use image_combiner::diff::diff_stats;
+use image_combiner::floating_image::{Channels, Precision};
+use image_combiner::output::{self, EncoderOptions};
+use image_combiner::size::standardize_size;
+use image_combiner::limits::DecodeLimits;
+use image_combiner::{check_stdin_once, find_image_from_path_with_limits, format_mismatch, grid, Combine, DynamicFloatingImage, ImageDataErrors, Warning, STDIO_PATH};
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+use std::process::ExitCode;
+
+
+fn main() -> ExitCode {
+    let error_format = ErrorFormat::from_args();
+
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        // Scripts asking for json get the argument errors as json too, help and --version are printed as normal
+        Err(e) if error_format == ErrorFormat::Json && e.use_stderr() => {
+            // The possible values, suggestions and missing arguments are on the lines after the first,
+            // everything up to the usage goes into the one line
+            let message = e.render().to_string();
+            let lines: Vec<&str> = message
+                .lines()
+                .take_while(|line| !line.starts_with("Usage:") && !line.starts_with("For more information"))
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .collect();
+            let error = ImageDataErrors::InvalidArguments(lines.join(" ").trim_start_matches("error: ").to_string());
+            report(&error, error_format);
+            return ExitCode::from(error.category().exit_code());
+        },
+        Err(e) => e.exit()
+    };
+
+    // Rayon's global pool is used for everything, so it only needs setting up once here
+    if let Some(jobs) = cli.jobs {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs as usize)
+            .build_global()
+            .expect("nothing has used the thread pool yet");
+    }
 
-use args::Args;
-use image::{io::Reader, ImageFormat, DynamicImage, GenericImageView, imageops::FilterType::Triangle, ImageError};
-use std::convert::TryInto;
-
-
-#[derive(Debug)]
-enum ImageDataErrors {
-    DifferentImageFormats,
-    BufferTooSmall,
-    UnableToReadImageFromPath(std::io::Error),
-    UnableToFormatImage(String),
-    UnableToDecodeImage(ImageError),
-    UnableToSaveImage(ImageError)
-
+    let limits = cli.limits.limits();
+    let result = match cli.command {
+        Command::Combine(args) => combine(args, cli.memory_budget, &limits),
+        Command::Batch(args) => run_batch(args, cli.memory_budget, &limits, cli.error_format),
+        Command::Diff(args) => diff(args, &limits),
+        Command::Grid(args) => grid(args, &limits),
+        Command::Split(args) => split(args, &limits),
+        Command::Info(args) => info(args, &limits),
+        Command::Completions { shell } => completions(shell)
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            report(&e, cli.error_format);
+            ExitCode::from(e.category().exit_code())
+        }
+    }
 }
 
-/// Acts as a temporary storage for Image meta data before being saved
-struct FloatingImage {
-    width: u32,
-    height: u32,
-    data: Vec<u8>, // Data will reserve memory for the pixel rgba values, u8 type because rgba stores values from 0 - 255 which is exact size that rgba goes up to
-    name: String
+/// Prints the completion script for the shell. It's generated into memory first since clap_complete
+/// panics if stdout is closed, e.g. piped into head
+fn completions(shell: clap_complete::Shell) -> Result<(), ImageDataErrors> {
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut Cli::command(), "image-combiner", &mut script);
+    let mut stdout = std::io::stdout().lock();
+    stdout
+        .write_all(&script)
+        .and_then(|_| stdout.flush())
+        .map_err(|e| ImageDataErrors::UnableToSaveImage(STDIO_PATH.to_string(), image::ImageError::IoError(e)))
 }
 
-impl FloatingImage {
-    fn new(width: u32, height: u32, name: String) -> Self {
-        let buffer_capacity = height * width * 4; // 4: number of pixels' rgba values
-        let buffer = Vec::with_capacity(buffer_capacity.try_into().unwrap());
+fn combine(args: CombineArgs, memory_budget: Option<u64>, limits: &DecodeLimits) -> Result<(), ImageDataErrors> {
+    let args = Args::new(args, limits)?;
 
-        FloatingImage {
-            width,
-            height,
-            data: buffer,
-            name
-        }
+    // Random patterns print their seed, so the same output can be made again
+    if let Some(seed) = args.options.pattern.seed() {
+        status(&args.output, format!("seed: {}", seed));
     }
-    // Methods on a struct take in self as first argument
 
-    fn set_data(&mut self, data: Vec<u8>) -> Result<(), ImageDataErrors> {
-        // If the data passed in is bigger than the capacity, means buffer is not big enough to hold onto input data
-        if data.len() > self.data.capacity() {
-            return Err(ImageDataErrors::BufferTooSmall)
-        }
-        self.data = data;
-        Ok(())
+    let mut combine = Combine::new()
+        .inputs(args.images)
+        .mode(args.mode)
+        .options(args.options)
+        .size_policy(args.size)
+        .encoder(args.encoder)
+        .decode_limits(*limits)
+        .on_warning(warn);
+    if let Some(budget) = memory_budget {
+        combine = combine.memory_budget(budget);
     }
-}
 
+    let saved = combine.save(args.output)?;
+    if saved.in_strips {
+        status(&saved.path, "combined a strip at a time to stay inside the memory budget".to_string());
+    }
+    let place = if saved.path == STDIO_PATH { "standard output" } else { saved.path.as_str() };
+    status(&saved.path, format!("saved {} ({}x{})", place, saved.width, saved.height));
 
-fn main() -> Result<(), ImageDataErrors> {
-    let args = Args::new();
-    let (image_1, image_1_format) = find_image_from_path(args.image_1)?;
-    let (image_2, image_2_format) = find_image_from_path(args.image_2)?;
+    Ok(())
+}
 
-    // if images aren't the same formats
-    if image_1_format != image_2_format {
-        return Err(ImageDataErrors::DifferentImageFormats);
+/// Combines every pair, reporting each failure as it happens without stopping, then prints a summary
+fn run_batch(args: BatchArgs, memory_budget: Option<u64>, limits: &DecodeLimits, error_format: ErrorFormat) -> Result<(), ImageDataErrors> {
+    let (left, right, manifest) = (args.left.clone(), args.right.clone(), args.manifest.clone());
+    let settings = Args::batch(args, limits)?;
+    let template: NameTemplate = settings.output.parse()?;
+
+    let (pairs, unpaired) = match (manifest, left, right) {
+        (Some(manifest), _, _) => (batch::read_manifest(&manifest)?, Vec::new()),
+        (None, Some(left), Some(right)) => batch::pair_directories(&left, &right)?,
+        // clap makes sure there's either a manifest or both folders
+        _ => unreachable!()
+    };
+
+    // Files that couldn't be paired can't be combined, but they count as failures so they don't go unnoticed
+    for error in &unpaired {
+        report(error, error_format);
     }
 
-    // Redeclare(shadow) image_1 and image_2 from resizing result
-    let (image_1, image_2) = standardize_size(image_1, image_2);
+    // The same seed for every item, so it only needs printing once
+    if let Some(seed) = settings.options.pattern.seed() {
+        println!("seed: {}", seed);
+    }
 
+    let mut combine = Combine::new()
+        .mode(settings.mode)
+        .options(settings.options)
+        .size_policy(settings.size)
+        .encoder(settings.encoder)
+        .decode_limits(*limits)
+        .on_warning(warn);
+    // Every thread could be combining a pair at the same time, so each gets a share of the budget
+    if let Some(budget) = memory_budget {
+        combine = combine.memory_budget(budget / rayon::current_num_threads() as u64);
+    }
 
-    let mut output = FloatingImage::new(image_1.width(), image_1.height(), args.output);
+    let outcomes = batch::run(&pairs, &combine, &template, |outcome| match &outcome.result {
+        Ok(()) => println!("ok: {} -> {}", outcome.name, outcome.output),
+        Err(e) => report(e, error_format)
+    });
 
-    let combined_data = combine_images(image_1, image_2);
-    output.set_data(combined_data)?;
+    let total = outcomes.len() + unpaired.len();
+    let failed = outcomes.iter().filter(|outcome| outcome.result.is_err()).count() + unpaired.len();
+    println!("batch: {} succeeded, {} failed", total - failed, failed);
 
-    if let Err(e) = image::save_buffer_with_format(
-        output.name, &output.data,
-        output.width, output.height,
-        image::ColorType::Rgba8, image_1_format) {
-            Err(ImageDataErrors::UnableToSaveImage(e))
-        } else {
-            Ok(())
-        }
+    if failed > 0 {
+        Err(ImageDataErrors::BatchFailed(failed, total))
+    } else {
+        Ok(())
+    }
 }
 
-/// Takes in path as a string, returns 2 DynamicImages from image crate
-fn find_image_from_path(path: String) -> Result<(DynamicImage, ImageFormat), ImageDataErrors> {
-    // Reader struct implements an open function which takes a path to an image file
-    // Returning the Result, unwrap the result (get result)
-    // let image_reader: Reader<BufReader<File>> = Reader::open(path).unwrap();
-    match Reader::open(&path) {
-        Ok(image_reader) => {
-
-            // Get's the image format from the unwrapped value (result) of image reader
-            // if let Some() : when dealing with Option
-            if let Some(image_format) = image_reader.format() {
-
-                match image_reader.decode() {
-                    // Return both values in a tuple (image and it's format)
-                    Ok(image) => Ok((image, image_format)),
-                    Err(e) => Err(ImageDataErrors::UnableToDecodeImage(e))
-                }
-            } else {
-                    return Err(ImageDataErrors::UnableToFormatImage(path))
-                }
-        },
-            Err(e) => Err(ImageDataErrors::UnableToReadImageFromPath(e))
+fn diff(args: DiffArgs, limits: &DecodeLimits) -> Result<(), ImageDataErrors> {
+    check_stdin_once([args.first.as_str(), args.second.as_str()])?;
+    let (first, format) = decode(args.first, limits)?;
+    let (second, _) = decode(args.second, limits)?;
+    let images = vec![first, second];
+    let precision = Precision::of(&images);
+    let channels = Channels::of(&images);
+
+    // Images of different sizes get brought to the same size first, the same way combine does it
+    let images = standardize_size(images, &args.size.policy()?);
+    let stats = diff_stats(&images[0], &images[1], args.threshold);
+
+    let output = args.output.as_deref().unwrap_or_default();
+    status(
+        output,
+        format!(
+            "pixels: {}, different: {} ({:.2}%)",
+            stats.pixels,
+            stats.differing_pixels,
+            stats.differing_pixels as f64 * 100.0 / stats.pixels.max(1) as f64
+        )
+    );
+    status(output, format!("max difference: {:.4}, mean difference: {:.4}", stats.max_difference, stats.mean_difference));
+    status(output, format!("psnr: {:.2} dB", stats.psnr));
+
+    if let Some(path) = args.output {
+        let registry = Registry::new(&CombineOptions::default());
+        let mut difference = registry.get("difference")?.combine(&images, precision)?;
+        difference.set_channels(channels);
+        save(difference, path, &args.encoder.options()?, format)?;
     }
+
+    Ok(())
 }
 
-/// Get's the smaller of the two images provided, returns height and width of type u32 of it
-fn get_smallest_dimensions(dim_1: (u32, u32), dim_2: (u32, u32)) -> (u32, u32) {
-    // Number of pixel in image_1 and image_2 to get size
-    let pix_1 = dim_1.0 * dim_1.1;
-    let pix_2 = dim_1.0 * dim_1.1;
+fn grid(args: GridArgs, limits: &DecodeLimits) -> Result<(), ImageDataErrors> {
+    let (images, format) = decode_all(args::expand_paths(args.images), limits)?;
+    let precision = Precision::of(&images);
+    let channels = Channels::of(&images);
+
+    // Roughly square unless told otherwise
+    let columns = args.columns.unwrap_or_else(|| (images.len() as f64).sqrt().ceil() as u32);
+    let images = standardize_size(images, &args.size.policy()?);
+    let grid = grid::grid(&images, columns, args.gap, args.gap_color);
 
-    // Return the smaller of the two dimensions provided
-    return if pix_1 < pix_2 { dim_1 } else { dim_2 }
+    let output = DynamicFloatingImage::from_image(&grid, precision, channels, String::new());
+    save(output, args.output, &args.encoder.options()?, format)
 }
 
-/// Resizes either of the two pictures to the smaller, returns both images, with the bigger one resized to the smaller
-fn standardize_size(image_1: DynamicImage, image_2: DynamicImage) -> (DynamicImage, DynamicImage) {
+fn split(args: SplitArgs, limits: &DecodeLimits) -> Result<(), ImageDataErrors> {
+    if args.output == STDIO_PATH {
+        return Err(ImageDataErrors::InvalidArguments("split saves a file for each tile, so it can't write to standard output".to_string()));
+    }
+    let (image, format) = decode(args.image, limits)?;
+    let images = [image];
+    let precision = Precision::of(&images);
+    let channels = Channels::of(&images);
+    let encoder = args.encoder.options()?;
+
+    let tiles = grid::split(&images[0], args.columns, args.rows);
+    let columns = args.columns.min(images[0].width().max(1)) as usize;
+    for (index, tile) in tiles.iter().enumerate() {
+        let path = tile_path(&args.output, index / columns, index % columns);
+        let output = DynamicFloatingImage::from_image(tile, precision, channels, String::new());
+        save(output, path, &encoder, format)?;
+    }
 
-    // Dimensions method comes from image crate
-    let (width, height) = get_smallest_dimensions(image_1.dimensions(), image_2.dimensions());
+    println!("tiles: {}", tiles.len());
+    Ok(())
+}
 
-    println!("width: {}, height {}", width, height);
+fn info(args: InfoArgs, limits: &DecodeLimits) -> Result<(), ImageDataErrors> {
+    for path in args::expand_paths(args.images) {
+        let (image, format) = decode(path.clone(), limits)?;
+        println!("{}: {:?}, {}x{}, {:?}", path, format, image.width(), image.height(), image.color());
+    }
+    Ok(())
+}
 
-    // If image_2 dimensions are the same as the smallest of the two provided images found, resize image_1
-    if image_2.dimensions() == (width, height) {
-        (image_1.resize_exact(width, height, Triangle), image_2)
-    } else {
-        // else if image_1 is equal to the smallest of the two provided images, resize image_2
-        (image_1, image_2.resize_exact(width, height, Triangle))
+/// Decodes the image at path, warning when it's named like a different format to what it is
+fn decode(path: String, limits: &DecodeLimits) -> Result<(DynamicImage, ImageFormat), ImageDataErrors> {
+    let (image, format) = find_image_from_path_with_limits(path.clone(), limits)?;
+    if let Some(warning) = format_mismatch(&path, format) {
+        warn(&warning);
     }
+    Ok((image, format))
 }
 
-// Takes in two images, returns the pixel values in a vector
-fn combine_images(image_1: DynamicImage, image_2: DynamicImage) -> Vec<u8> {
-    // DynamicImage struct implements to_rgb8 method, which returns
-    // an ImageBuffer which contains a Vec<u8>, and implements into_vec method which returns the vec itself
-    let vec_1 = image_1.to_rgba8().into_vec();
-    let vec_2 = image_2.to_rgba8().into_vec();
+/// Decodes every path, returns the images along with the format of the first one
+fn decode_all(paths: Vec<String>, limits: &DecodeLimits) -> Result<(Vec<DynamicImage>, ImageFormat), ImageDataErrors> {
+    check_stdin_once(paths.iter().map(String::as_str))?;
+    let mut images = Vec::with_capacity(paths.len());
+    let mut first_format = None;
+    for path in paths {
+        let (image, format) = decode(path, limits)?;
+        images.push(image);
+        first_format = first_format.or(Some(format));
+    }
 
-    alternate_pixels(vec_1, vec_2)
+    // clap makes sure there's at least one path, though a glob can still match nothing
+    let format = first_format.ok_or(ImageDataErrors::NotEnoughImages(0))?;
+    Ok((images, format))
 }
 
-fn alternate_pixels(vec_1: Vec<u8>, vec_2: Vec<u8>) -> Vec<u8> {
-    // holds the data after the two images have been alternated,
-    // pushing pixels from one vector then the other; alternating
-    // To size the combined data, it is matched using vec macro of u8's,
-    // and the number of u8's being the same number as vec_1
-
-    let mut combined_data = vec![0u8; vec_1.len()];
+/// Saves the image to path in the format the encoder options or path ask for, otherwise the input's format
+fn save(mut image: DynamicFloatingImage, path: String, encoder: &EncoderOptions, input_format: ImageFormat) -> Result<(), ImageDataErrors> {
+    let format = output::output_format(&path, encoder, input_format)?;
+    image.set_name(path);
+    if let Some(warning) = output::save(&image, format, encoder)? {
+        warn(&warning);
+    }
+    Ok(())
+}
 
-    let mut i = 0;
+/// The library leaves printing warnings to whoever's using it, they always go to stderr
+fn warn(warning: &Warning) {
+    eprintln!("warning: {}", warning);
+}
 
-    // Go over each pixel in the size of the vector
-    while i < vec_1.len() {
-        if i % 8 == 0 {
-            // vec_1 is referenced so that it doesn't get passed into the set_rgba function, but a reference does
-            combined_data.splice(i ..=i + 3, set_rgba(&vec_1, i, i + 3));
-        } else {
-            combined_data.splice(i..=i + 3, set_rgba(&vec_2, i, i + 3));
-        }
-        // 4 because rgba, as accessing the indicies in the vector using modulus
-        i += 4;
+/// Prints a line about how it went. When the image itself is going to standard output it's printed to
+/// stderr instead, so it doesn't end up in the middle of the image
+fn status(output: &str, message: String) {
+    if output == STDIO_PATH {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
     }
-    combined_data
 }
 
-// Takes in a vector, a starting index, and and ending an index and returns the rgba set from those indices
-fn set_rgba(vec: &Vec<u8>, start: usize, end: usize) -> Vec<u8> {
-    let mut rgba = Vec::new();
+/// `tiles.png` becomes `tiles_ROW_COLUMN.png`
+fn tile_path(output: &str, row: usize, column: usize) -> String {
+    let path = Path::new(output);
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(extension) => format!("{}_{}_{}.{}", stem, row, column, extension.to_string_lossy()),
+        None => format!("{}_{}_{}", stem, row, column)
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
 
-    // for every index from start to end inclusive
-    for i in start..=end {
-        // new variable
-        // match in case the value doesn't exist
-        let val: u8 = match vec.get(i) {
-            Some(v) => *v, // de-referene so that it's not pushing reference values
-            None => panic!("Index out of bounds")
-        };
-        rgba.push(val);
+/// Prints the error and everything that caused it to stderr, as text or as a single line of JSON
+fn report(error: &ImageDataErrors, format: ErrorFormat) {
+    match format {
+        ErrorFormat::Text => {
+            eprintln!("error: {}", error);
+            for cause in causes(error) {
+                eprintln!("  caused by: {}", cause);
+            }
+        },
+        ErrorFormat::Json => eprintln!("{}", json_report(error))
     }
+}
 
-    rgba
+/// Walks down the sources e.g. unable to decode `a.png` <- format error <- unexpected end of file
+fn causes(error: &ImageDataErrors) -> Vec<String> {
+    let mut causes = Vec::new();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        causes.push(cause.to_string());
+        source = cause.source();
+    }
+    causes
+}
+
+/// The line `--error-format json` prints, scripts depend on these keys so they shouldn't change
+fn json_report(error: &ImageDataErrors) -> serde_json::Value {
+    serde_json::json!({
+        "error": error.to_string(),
+        "category": error.category().name(),
+        "exit_code": error.category().exit_code(),
+        "path": error.path(),
+        "causes": causes(error)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_reports_keep_their_keys() {
+        let io = std::io::Error::new(std::io::ErrorKind::NotFound, "no such file");
+        let report = json_report(&ImageDataErrors::UnableToReadImageFromPath("a.png".to_string(), io));
+        assert_eq!(
+            report,
+            serde_json::json!({
+                "error": "unable to read `a.png`",
+                "category": "input",
+                "exit_code": 3,
+                "path": "a.png",
+                "causes": ["no such file"]
+            })
+        );
+
+        // Errors that aren't about a file still have every key, path is null
+        let report = json_report(&ImageDataErrors::NotEnoughImages(1));
+        assert_eq!(report["path"], serde_json::Value::Null);
+        assert_eq!(report["category"], "usage");
+        assert_eq!(report["exit_code"], 2);
+        assert_eq!(report["causes"], serde_json::json!([]));
+    }
 }
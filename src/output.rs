@@ -0,0 +1,808 @@
+use crate::floating_image::{Channels, DynamicFloatingImage, Precision, Sample};
+use crate::size::parse_color;
+use crate::{ImageDataErrors, Warning, STDIO_PATH};
+use image::codecs::hdr::HdrEncoder;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::codecs::pnm::{ArbitraryHeader, ArbitraryTuplType, PnmEncoder};
+use image::codecs::webp::{WebPEncoder, WebPQuality};
+use image::error::{EncodingError, ImageFormatHint};
+use image::{ColorType, ImageEncoder, ImageError, ImageFormat, Rgb, Rgba};
+use std::borrow::Cow;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Cursor, Seek, Write};
+use std::path::Path;
+use std::str::FromStr;
+use tiff::encoder::compression::{CompressionAlgorithm, Compressor};
+use tiff::encoder::{colortype, compression, Rational, TiffEncoder, TiffKind};
+use tiff::tags::{CompressionMethod, ResolutionUnit, Tag};
+
+/// Compression used for TIFF output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TiffCompression {
+    None,
+    Lzw,
+    Deflate,
+    Packbits
+}
+
+/// What see through pixels get laid on top of, for formats that can't store transparency
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Background {
+    Color(Rgba<u8>),
+    /// Light grey and white squares of the given size, the usual way of showing transparency
+    Checkerboard(u32)
+}
+
+/// How the output gets encoded: which format, and the settings for that format's encoder
+#[derive(Debug, Clone)]
+pub struct EncoderOptions {
+    // Picked with --format, otherwise the output path's extension decides
+    pub format: Option<ImageFormat>,
+    // 1 - 100, higher is better quality but a bigger file
+    pub jpeg_quality: u8,
+    pub progressive: bool,
+    pub png_compression: CompressionType,
+    pub png_filter: FilterType,
+    // Lossless when not set, otherwise the lossy quality from 0 - 100
+    pub webp_quality: Option<u8>,
+    pub tiff_compression: TiffCompression,
+    pub background: Background
+}
+
+impl Default for EncoderOptions {
+    // Matches what the image crate's encoders do when given no settings
+    fn default() -> Self {
+        EncoderOptions {
+            format: None,
+            jpeg_quality: 75,
+            progressive: false,
+            png_compression: CompressionType::default(),
+            png_filter: FilterType::default(),
+            webp_quality: None,
+            tiff_compression: TiffCompression::None,
+            background: Background::Color(Rgba([255, 255, 255, 255]))
+        }
+    }
+}
+
+impl FromStr for TiffCompression {
+    type Err = ImageDataErrors;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(TiffCompression::None),
+            "lzw" => Ok(TiffCompression::Lzw),
+            "deflate" => Ok(TiffCompression::Deflate),
+            "packbits" => Ok(TiffCompression::Packbits),
+            _ => Err(ImageDataErrors::InvalidEncoderOption(value.to_string()))
+        }
+    }
+}
+
+impl FromStr for Background {
+    type Err = ImageDataErrors;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || ImageDataErrors::InvalidEncoderOption(value.to_string());
+
+        match value.split_once(':') {
+            Some(("checkerboard", size)) => match size.parse() {
+                Ok(size) if size > 0 => Ok(Background::Checkerboard(size)),
+                _ => Err(invalid())
+            },
+            Some(_) => Err(invalid()),
+            None if value == "checkerboard" => Ok(Background::Checkerboard(8)),
+            None => parse_color(value).map(Background::Color).ok_or_else(invalid)
+        }
+    }
+}
+
+/// Written the same way it's parsed, e.g. `#ffffff` or `checkerboard:8`
+impl fmt::Display for Background {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Background::Color(Rgba([red, green, blue, 255])) => write!(f, "#{:02x}{:02x}{:02x}", red, green, blue),
+            Background::Color(Rgba([red, green, blue, alpha])) => write!(f, "#{:02x}{:02x}{:02x}{:02x}", red, green, blue, alpha),
+            Background::Checkerboard(size) => write!(f, "checkerboard:{}", size)
+        }
+    }
+}
+
+impl Background {
+    /// Colour of the background at (x, y)
+    fn color_at(&self, x: u32, y: u32) -> Rgba<u8> {
+        match self {
+            Background::Color(color) => *color,
+            Background::Checkerboard(size) => {
+                if (x / size + y / size).is_multiple_of(2) {
+                    Rgba([255, 255, 255, 255])
+                } else {
+                    Rgba([204, 204, 204, 255])
+                }
+            }
+        }
+    }
+}
+
+/// What the format is usually called, for messages e.g. `JPEG` rather than `Jpeg`
+pub fn format_name(format: ImageFormat) -> String {
+    let name = match format {
+        ImageFormat::Png => "PNG",
+        ImageFormat::Jpeg => "JPEG",
+        ImageFormat::Gif => "GIF",
+        ImageFormat::WebP => "WebP",
+        ImageFormat::Pnm => "PNM",
+        ImageFormat::Tiff => "TIFF",
+        ImageFormat::Tga => "TGA",
+        ImageFormat::Dds => "DDS",
+        ImageFormat::Bmp => "BMP",
+        ImageFormat::Ico => "ICO",
+        ImageFormat::Hdr => "HDR",
+        ImageFormat::OpenExr => "OpenEXR",
+        ImageFormat::Farbfeld => "farbfeld",
+        ImageFormat::Avif => "AVIF",
+        ImageFormat::Qoi => "QOI",
+        // Formats added to the image crate after this was written
+        _ => return format!("{:?}", format)
+    };
+    name.to_string()
+}
+
+/// Whether the format can store an alpha channel. PAMs can, but the image crate can't read them back
+fn supports_alpha(format: ImageFormat) -> bool {
+    !matches!(format, ImageFormat::Jpeg | ImageFormat::Hdr | ImageFormat::Pnm)
+}
+
+/// Parses a format from its name or usual extension e.g. `png`, `jpg`, `tiff`
+pub fn parse_format(value: &str) -> Result<ImageFormat, ImageDataErrors> {
+    ImageFormat::from_extension(value).ok_or_else(|| ImageDataErrors::InvalidEncoderOption(value.to_string()))
+}
+
+/// Parses a PNG compression level: fast, default or best
+pub fn parse_png_compression(value: &str) -> Result<CompressionType, ImageDataErrors> {
+    match value {
+        "fast" => Ok(CompressionType::Fast),
+        "default" => Ok(CompressionType::Default),
+        "best" => Ok(CompressionType::Best),
+        _ => Err(ImageDataErrors::InvalidEncoderOption(value.to_string()))
+    }
+}
+
+/// Parses a PNG filter: none, sub, up, avg, paeth or adaptive
+pub fn parse_png_filter(value: &str) -> Result<FilterType, ImageDataErrors> {
+    match value {
+        "none" => Ok(FilterType::NoFilter),
+        "sub" => Ok(FilterType::Sub),
+        "up" => Ok(FilterType::Up),
+        "avg" => Ok(FilterType::Avg),
+        "paeth" => Ok(FilterType::Paeth),
+        "adaptive" => Ok(FilterType::Adaptive),
+        _ => Err(ImageDataErrors::InvalidEncoderOption(value.to_string()))
+    }
+}
+
+/// Works out the format to save in: --format if it was given, otherwise the output's extension.
+/// Only when the path has no extension at all does the fallback get used, a mistyped one like `.pngg`
+/// is an error rather than quietly saving in some other format. Standard output has to be given a format,
+/// whatever's reading it can't be expected to guess
+pub fn output_format(path: &str, options: &EncoderOptions, fallback: ImageFormat) -> Result<ImageFormat, ImageDataErrors> {
+    let missing = || ImageDataErrors::MissingOutputFormat(path.to_string());
+    match options.format {
+        Some(format) => Ok(format),
+        None if path == STDIO_PATH => Err(missing()),
+        None if Path::new(path).extension().is_none() => Ok(fallback),
+        None => ImageFormat::from_path(path).map_err(|_| missing())
+    }
+}
+
+/// The most precise sample type the format can store that doesn't go beyond what the image has,
+/// e.g. a 16-bit image saved as a JPEG gets brought down to 8 bits
+pub(crate) fn output_precision(format: ImageFormat, precision: Precision) -> Precision {
+    match format {
+        ImageFormat::Png | ImageFormat::Pnm => precision.min(Precision::U16),
+        ImageFormat::Tiff => precision,
+        // These formats only come in one precision
+        ImageFormat::OpenExr | ImageFormat::Hdr => Precision::F32,
+        ImageFormat::Farbfeld => Precision::U16,
+        _ => Precision::U8
+    }
+}
+
+/// Saves the image to the path in its name, `-` writes it to standard output.
+/// The file is only created once the image has been encoded, so a failed save leaves what was there alone.
+/// Returns the warning to pass on when see through pixels had to be flattened
+pub fn save(image: &DynamicFloatingImage, format: ImageFormat, options: &EncoderOptions) -> Result<Option<Warning>, ImageDataErrors> {
+    let (encoded, warning) = encode_to_memory(image, format, options)?;
+    if image.name() == STDIO_PATH {
+        write_encoded(image, &encoded, io::stdout().lock())?;
+        return Ok(warning);
+    }
+    let file = File::create(image.name()).map_err(|e| ImageDataErrors::UnableToSaveImage(image.name().to_string(), ImageError::IoError(e)))?;
+    write_encoded(image, &encoded, BufWriter::new(file))?;
+    Ok(warning)
+}
+
+/// Encodes the image in the format given and writes it out to writer, returning a warning the same as save
+pub fn encode<W: Write>(image: &DynamicFloatingImage, format: ImageFormat, options: &EncoderOptions, writer: W) -> Result<Option<Warning>, ImageDataErrors> {
+    let (encoded, warning) = encode_to_memory(image, format, options)?;
+    write_encoded(image, &encoded, writer)?;
+    Ok(warning)
+}
+
+/// Some encoders need to jump back and forth in what they write, so everything gets encoded
+/// into memory first and written out in one go
+fn encode_to_memory(image: &DynamicFloatingImage, format: ImageFormat, options: &EncoderOptions) -> Result<(Vec<u8>, Option<Warning>), ImageDataErrors> {
+    let mut buffer = Cursor::new(Vec::new());
+    let dimensions = image.dimensions();
+    let channels = image.channels();
+
+    let result = match output_precision(format, image.precision()) {
+        Precision::U8 => encode_samples(&image.samples::<u8>(), dimensions, channels, format, options, &mut buffer),
+        Precision::U16 => encode_samples(&image.samples::<u16>(), dimensions, channels, format, options, &mut buffer),
+        Precision::F32 => encode_samples(&image.samples::<f32>(), dimensions, channels, format, options, &mut buffer)
+    };
+    let warning = result.map_err(|e| ImageDataErrors::UnableToSaveImage(image.name().to_string(), e))?;
+    Ok((buffer.into_inner(), warning))
+}
+
+/// Writes out the bytes encode_to_memory gave
+fn write_encoded<W: Write>(image: &DynamicFloatingImage, encoded: &[u8], mut writer: W) -> Result<(), ImageDataErrors> {
+    writer
+        .write_all(encoded)
+        .and_then(|_| writer.flush())
+        .map_err(|e| ImageDataErrors::UnableToSaveImage(image.name().to_string(), ImageError::IoError(e)))
+}
+
+/// Encodes rgba samples of type T with the format's encoder
+fn encode_samples<T: Sample>(samples: &[T], dimensions: (u32, u32), channels: Channels, format: ImageFormat, options: &EncoderOptions, buffer: &mut Cursor<Vec<u8>>) -> Result<Option<Warning>, ImageError> {
+    let (width, height) = dimensions;
+    let (data, color, warning) = prepare_pixels(samples, width, channels, format, options);
+    // The image crate's encoders take the samples as native endian bytes
+    let bytes: &[u8] = bytemuck::cast_slice(&data);
+
+    let result = match format {
+        ImageFormat::Jpeg => encode_jpeg(bytes, width, height, color, options, buffer),
+        ImageFormat::Png => PngEncoder::new_with_quality(buffer, options.png_compression, options.png_filter)
+            .write_image(bytes, width, height, color),
+        ImageFormat::WebP => {
+            let encoder = match options.webp_quality {
+                // Lossy WebP is deprecated in the image crate, but it's the only way to get it
+                #[allow(deprecated)]
+                Some(quality) => WebPEncoder::new_with_quality(buffer, WebPQuality::lossy(quality)),
+                None => WebPEncoder::new_lossless(buffer)
+            };
+            encoder.write_image(bytes, width, height, color)
+        },
+        ImageFormat::Tiff => encode_tiff(&data, width, height, color, options.tiff_compression, buffer),
+        ImageFormat::Pnm => encode_pnm(&data, width, height, color, buffer),
+        ImageFormat::Hdr => encode_hdr(&data, width, height, buffer),
+        // Formats without any settings go through the image crate as they always have
+        _ => image::write_buffer_with_format(buffer, bytes, width, height, color, format)
+    };
+    result.map(|()| warning)
+}
+
+/// Wraps an error from one of the encoders outside of the image crate so it can be reported the same way
+fn encoding_error<E>(format: ImageFormat, error: E) -> ImageError
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>
+{
+    ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(format), error))
+}
+
+/// The image crate's JPEG encoder can't write progressive JPEGs, so jpeg-encoder is used instead
+fn encode_jpeg<W: Write>(data: &[u8], width: u32, height: u32, color: ColorType, options: &EncoderOptions, writer: W) -> Result<(), ImageError> {
+    // JPEGs can't be more than 65535 pixels on a side
+    let width = u16::try_from(width).map_err(|e| encoding_error(ImageFormat::Jpeg, e))?;
+    let height = u16::try_from(height).map_err(|e| encoding_error(ImageFormat::Jpeg, e))?;
+    let color = match color {
+        ColorType::L8 => jpeg_encoder::ColorType::Luma,
+        ColorType::Rgb8 => jpeg_encoder::ColorType::Rgb,
+        _ => jpeg_encoder::ColorType::Rgba
+    };
+
+    let mut encoder = jpeg_encoder::Encoder::new(writer, options.jpeg_quality.clamp(1, 100));
+    encoder.set_progressive(options.progressive);
+    encoder
+        .encode(data, width, height, color)
+        .map_err(|e| encoding_error(ImageFormat::Jpeg, e))
+}
+
+/// The image crate's TIFF encoder can't compress or write floating point, so the tiff crate is used directly.
+/// The colour type says which type T really is, so the samples can be cast to it
+fn encode_tiff<T: Sample>(data: &[T], width: u32, height: u32, color: ColorType, compression: TiffCompression, writer: &mut Cursor<Vec<u8>>) -> Result<(), ImageError> {
+    let mut encoder = TiffEncoder::new(writer).map_err(|e| encoding_error(ImageFormat::Tiff, e))?;
+    let image = (width, height, compression);
+
+    let result = match color {
+        ColorType::L8 => write_tiff::<colortype::Gray8>(&mut encoder, bytemuck::cast_slice(data), image),
+        ColorType::Rgb8 => write_tiff::<colortype::RGB8>(&mut encoder, bytemuck::cast_slice(data), image),
+        ColorType::L16 => write_tiff::<colortype::Gray16>(&mut encoder, bytemuck::cast_slice(data), image),
+        ColorType::Rgb16 => write_tiff::<colortype::RGB16>(&mut encoder, bytemuck::cast_slice(data), image),
+        ColorType::Rgba16 => write_tiff::<colortype::RGBA16>(&mut encoder, bytemuck::cast_slice(data), image),
+        ColorType::Rgb32F => write_tiff::<colortype::RGB32Float>(&mut encoder, bytemuck::cast_slice(data), image),
+        ColorType::Rgba32F => write_tiff::<colortype::RGBA32Float>(&mut encoder, bytemuck::cast_slice(data), image),
+        _ => write_tiff::<colortype::RGBA8>(&mut encoder, bytemuck::cast_slice(data), image)
+    };
+    result.map_err(|e| encoding_error(ImageFormat::Tiff, e))
+}
+
+/// The image crate's PNM encoder counts the bytes of 16-bit images as samples, so those are given to it as u16s
+fn encode_pnm<T: Sample>(data: &[T], width: u32, height: u32, color: ColorType, writer: &mut Cursor<Vec<u8>>) -> Result<(), ImageError> {
+    let mut encoder = PnmEncoder::new(writer);
+    // The encoder turns down 16-bit colour under the RGB tuple type, even though the decoder reads it fine.
+    // A custom tuple type skips that check and gets written out as the same `TUPLTYPE RGB`
+    if color == ColorType::Rgb16 {
+        let header = ArbitraryHeader { width, height, depth: 3, maxval: u16::MAX as u32, tupltype: Some(ArbitraryTuplType::Custom("RGB".to_string())) };
+        encoder = encoder.with_header(header.into());
+    }
+    match T::PRECISION {
+        Precision::U16 => encoder.encode(bytemuck::cast_slice::<T, u16>(data), width, height, color),
+        // output_precision never gives PNMs floating point, so anything else is u8
+        _ => encoder.encode(bytemuck::cast_slice::<T, u8>(data), width, height, color)
+    }
+}
+
+/// The image crate has an HDR encoder but write_buffer_with_format doesn't know about it.
+/// output_precision and output_channels make sure HDRs always get rgb f32 samples
+fn encode_hdr<T: Sample>(data: &[T], width: u32, height: u32, writer: &mut Cursor<Vec<u8>>) -> Result<(), ImageError> {
+    let pixels: Vec<Rgb<f32>> = data
+        .chunks_exact(3)
+        .map(|pixel| Rgb([pixel[0].to_unit(), pixel[1].to_unit(), pixel[2].to_unit()]))
+        .collect();
+    HdrEncoder::new(writer).encode(&pixels, width as usize, height as usize)
+}
+
+/// Writes a TIFF with the tiff crate's colour type C, the compression is picked at run time
+/// but the tiff crate needs it as a type, hence the match
+fn write_tiff<C>(encoder: &mut TiffEncoder<&mut Cursor<Vec<u8>>>, data: &[C::Inner], image: (u32, u32, TiffCompression)) -> tiff::TiffResult<()>
+where
+    C: colortype::ColorType,
+    [C::Inner]: tiff::encoder::TiffValue
+{
+    let (width, height, tiff_compression) = image;
+    match tiff_compression {
+        TiffCompression::None => encoder.write_image_with_compression::<C, _>(width, height, compression::Uncompressed, data),
+        TiffCompression::Lzw => encoder.write_image_with_compression::<C, _>(width, height, compression::Lzw, data),
+        TiffCompression::Deflate => encoder.write_image_with_compression::<C, _>(width, height, compression::Deflate::default(), data),
+        TiffCompression::Packbits => encoder.write_image_with_compression::<C, _>(width, height, compression::Packbits, data)
+    }
+}
+
+/// Gets the rgba samples ready for the format's encoder. Formats that can't store alpha get the image
+/// flattened onto the background, then only the channels that are needed are kept.
+/// Flattening see through pixels comes back as a warning
+fn prepare_pixels<'a, T: Sample>(samples: &'a [T], width: u32, channels: Channels, format: ImageFormat, options: &EncoderOptions) -> (Cow<'a, [T]>, ColorType, Option<Warning>) {
+    let transparent = samples.chunks_exact(4).any(|pixel| pixel[3] != T::OPAQUE);
+
+    let (samples, alpha, warning) = if supports_alpha(format) {
+        // Combining can make see through pixels out of opaque inputs (e.g. the out operator), so alpha is kept for those too
+        (Cow::Borrowed(samples), channels.has_alpha() || transparent, None)
+    } else {
+        let warning = transparent.then_some(Warning::TransparencyFlattened(format, options.background));
+        (Cow::Owned(flatten(samples, width, &options.background)), false, warning)
+    };
+
+    // The same goes for colour, padding or a background can add colour to greyscale inputs
+    let color = channels.has_color() || samples.chunks_exact(4).any(|pixel| pixel[0] != pixel[1] || pixel[1] != pixel[2]);
+    let channels = output_channels::<T>(format, Channels::new(color, alpha));
+    let color_type = T::color_type(channels.count()).expect("output_channels only picks colour types the sample type has");
+
+    (narrow(samples, channels), color_type, warning)
+}
+
+/// Keeps only the samples of each rgba pixel that the channels need
+fn narrow<T: Sample>(samples: Cow<'_, [T]>, channels: Channels) -> Cow<'_, [T]> {
+    if channels == Channels::Rgba {
+        return samples;
+    }
+
+    let indices = channels.indices();
+    let narrowed = samples
+        .chunks_exact(4)
+        .flat_map(|pixel| indices.iter().map(move |&index| pixel[index]))
+        .collect();
+    Cow::Owned(narrowed)
+}
+
+/// Lays every pixel on top of the background, so they all come out opaque
+fn flatten<T: Sample>(samples: &[T], width: u32, background: &Background) -> Vec<T> {
+    let width = width.max(1);
+    samples
+        .chunks_exact(4)
+        .enumerate()
+        .flat_map(|(index, pixel)| {
+            let background = background.color_at(index as u32 % width, index as u32 / width);
+            let alpha = pixel[3].to_unit();
+            // Simple alpha blend of the pixel over the background
+            let [r, g, b] = [0, 1, 2].map(|channel| {
+                T::from_unit(pixel[channel].to_unit() * alpha + background.0[channel] as f32 / 255.0 * (1.0 - alpha))
+            });
+            [r, g, b, T::OPAQUE]
+        })
+        .collect()
+}
+
+/// The narrowest channels that hold everything in wanted, out of the ones both the format
+/// and the sample type can store. Going wider never loses anything, so that's the fallback
+fn output_channels<T: Sample>(format: ImageFormat, wanted: Channels) -> Channels {
+    let candidates: &[Channels] = match wanted {
+        Channels::L => &[Channels::L, Channels::Rgb, Channels::La, Channels::Rgba],
+        Channels::La => &[Channels::La, Channels::Rgba],
+        Channels::Rgb => &[Channels::Rgb, Channels::Rgba],
+        Channels::Rgba => &[Channels::Rgba]
+    };
+
+    candidates
+        .iter()
+        .copied()
+        .find(|channels| T::color_type(channels.count()).is_some() && supports_channels(format, *channels))
+        .unwrap_or(Channels::Rgba)
+}
+
+/// Whether the format's encoder takes pixels with these channels
+fn supports_channels(format: ImageFormat, channels: Channels) -> bool {
+    match format {
+        ImageFormat::Jpeg | ImageFormat::Hdr => !channels.has_alpha(),
+        ImageFormat::Gif | ImageFormat::Qoi => channels.has_color(),
+        // ICOs hold PNGs, which the decoder only reads back as rgba
+        ImageFormat::Farbfeld | ImageFormat::Ico => channels == Channels::Rgba,
+        ImageFormat::Pnm => !channels.has_alpha(),
+        // The tiff crate doesn't have greyscale with alpha
+        ImageFormat::Tiff => channels != Channels::La,
+        _ => true
+    }
+}
+
+/// Whether the format can be written a strip of rows at a time
+pub fn supports_strips(format: ImageFormat) -> bool {
+    matches!(format, ImageFormat::Png | ImageFormat::Tiff)
+}
+
+/// Saves an image to path a strip of rows at a time, so the whole of it never has to be in memory.
+/// Only formats supports_strips says yes to can be written like this. next_strip gives the rgba samples
+/// of each strip in turn, they're all rows_per_strip tall apart from the last one. Unlike save the channels
+/// can't be worked out from the pixels as they aren't all there, so they have to be given
+pub(crate) fn save_strips<T, F>(path: &str, format: ImageFormat, dimensions: (u32, u32), channels: Channels, rows_per_strip: u32, options: &EncoderOptions, mut next_strip: F) -> Result<(), ImageDataErrors>
+where
+    T: Sample,
+    F: FnMut() -> Result<Vec<T>, ImageDataErrors>
+{
+    let save_error = |e: ImageError| ImageDataErrors::UnableToSaveImage(path.to_string(), e);
+    let create = || File::create(path).map(BufWriter::new).map_err(|e| save_error(ImageError::IoError(e)));
+
+    let channels = output_channels::<T>(format, channels);
+    let mut strips = || next_strip().map(|strip| narrow(Cow::Owned(strip), channels).into_owned());
+
+    match format {
+        ImageFormat::Png if path == STDIO_PATH => write_png_strips(BufWriter::new(io::stdout()), dimensions, channels, options, &mut strips, &save_error),
+        ImageFormat::Png => write_png_strips(create()?, dimensions, channels, options, &mut strips, &save_error),
+        // The strip offsets get written at the end, so TIFFs need to be able to go back to the start
+        ImageFormat::Tiff if path == STDIO_PATH => Err(save_error(encoding_error(format, "TIFFs can't be written to standard output a strip at a time"))),
+        ImageFormat::Tiff => {
+            let color = T::color_type(channels.count()).expect("output_channels only picks colour types the sample type has");
+            write_tiff_strips(create()?, dimensions, color, rows_per_strip, options.tiff_compression, &mut strips, &save_error)
+        },
+        _ => Err(save_error(encoding_error(format, "this format can't be written a strip at a time")))
+    }
+}
+
+/// Writes the strips with the png crate, which the image crate's encoder is built on but which can also take
+/// the pixels a bit at a time
+fn write_png_strips<T: Sample, W: Write + 'static>(
+    writer: W,
+    dimensions: (u32, u32),
+    channels: Channels,
+    options: &EncoderOptions,
+    strips: &mut dyn FnMut() -> Result<Vec<T>, ImageDataErrors>,
+    save_error: &dyn Fn(ImageError) -> ImageDataErrors
+) -> Result<(), ImageDataErrors> {
+    let png_error = |e: png::EncodingError| save_error(encoding_error(ImageFormat::Png, e));
+    let (width, height) = dimensions;
+
+    // The same settings the image crate's PngEncoder picks for these options
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(match channels {
+        Channels::L => png::ColorType::Grayscale,
+        Channels::La => png::ColorType::GrayscaleAlpha,
+        Channels::Rgb => png::ColorType::Rgb,
+        Channels::Rgba => png::ColorType::Rgba
+    });
+    // output_precision never gives PNGs floating point
+    encoder.set_depth(if T::PRECISION == Precision::U16 { png::BitDepth::Sixteen } else { png::BitDepth::Eight });
+    encoder.set_compression(match options.png_compression {
+        CompressionType::Default => png::Compression::Default,
+        CompressionType::Best => png::Compression::Best,
+        _ => png::Compression::Fast
+    });
+    let (filter, adaptive) = match options.png_filter {
+        FilterType::NoFilter => (png::FilterType::NoFilter, png::AdaptiveFilterType::NonAdaptive),
+        FilterType::Sub => (png::FilterType::Sub, png::AdaptiveFilterType::NonAdaptive),
+        FilterType::Up => (png::FilterType::Up, png::AdaptiveFilterType::NonAdaptive),
+        FilterType::Avg => (png::FilterType::Avg, png::AdaptiveFilterType::NonAdaptive),
+        FilterType::Paeth => (png::FilterType::Paeth, png::AdaptiveFilterType::NonAdaptive),
+        _ => (png::FilterType::Sub, png::AdaptiveFilterType::Adaptive)
+    };
+    encoder.set_filter(filter);
+    encoder.set_adaptive_filter(adaptive);
+
+    let mut stream = encoder.write_header().and_then(|writer| writer.into_stream_writer()).map_err(png_error)?;
+    let mut rows = 0;
+    while rows < height {
+        let strip = strips()?;
+        rows += (strip.len() / (width.max(1) as usize * channels.count() as usize)) as u32;
+
+        // PNGs store 16-bit samples big endian
+        let written = match T::PRECISION {
+            Precision::U16 => {
+                let bytes: Vec<u8> = bytemuck::cast_slice::<T, u16>(&strip).iter().flat_map(|sample| sample.to_be_bytes()).collect();
+                stream.write_all(&bytes)
+            },
+            _ => stream.write_all(bytemuck::cast_slice(&strip))
+        };
+        written.map_err(|e| save_error(ImageError::IoError(e)))?;
+    }
+    stream.finish().map_err(png_error)
+}
+
+/// Writes the strips with the tiff crate. Its ImageEncoder only compresses when given the whole image,
+/// so each strip gets compressed here and the directory written by hand the same way the ImageEncoder does it
+fn write_tiff_strips<T: Sample, W: Write + Seek>(
+    writer: W,
+    dimensions: (u32, u32),
+    color: ColorType,
+    rows_per_strip: u32,
+    tiff_compression: TiffCompression,
+    strips: &mut dyn FnMut() -> Result<Vec<T>, ImageDataErrors>,
+    save_error: &dyn Fn(ImageError) -> ImageDataErrors
+) -> Result<(), ImageDataErrors> {
+    let tiff_error = |e: tiff::TiffError| save_error(encoding_error(ImageFormat::Tiff, e));
+
+    let (compressor, method) = match tiff_compression {
+        TiffCompression::None => (Compressor::Uncompressed(compression::Uncompressed), CompressionMethod::None),
+        TiffCompression::Lzw => (Compressor::Lzw(compression::Lzw), CompressionMethod::LZW),
+        TiffCompression::Deflate => (Compressor::Deflate(compression::Deflate::default()), CompressionMethod::Deflate),
+        TiffCompression::Packbits => (Compressor::Packbits(compression::Packbits), CompressionMethod::PackBits)
+    };
+    let image = TiffStrips { dimensions, rows_per_strip, compressor, method };
+
+    // Classic TIFFs can't point past 4 GiB, which only turns up once that much has been written.
+    // Compression can make the odd strip a bit bigger, so BigTIFF gets used well before then
+    let (width, height) = dimensions;
+    let uncompressed = width as u64 * height as u64 * color.bytes_per_pixel() as u64;
+    if uncompressed > BIG_TIFF_THRESHOLD {
+        image.write_color(TiffEncoder::new_big(writer).map_err(tiff_error)?, color, strips, &tiff_error)
+    } else {
+        image.write_color(TiffEncoder::new(writer).map_err(tiff_error)?, color, strips, &tiff_error)
+    }
+}
+
+/// Uncompressed size over which TIFFs written a strip at a time are BigTIFFs
+const BIG_TIFF_THRESHOLD: u64 = 3 << 30;
+
+/// Everything about a TIFF being written a strip at a time apart from its colour type
+struct TiffStrips {
+    dimensions: (u32, u32),
+    rows_per_strip: u32,
+    compressor: Compressor,
+    method: CompressionMethod
+}
+
+impl TiffStrips {
+    /// Picks the tiff crate's colour type for the image crate's one
+    fn write_color<W, K, T>(
+        self,
+        mut encoder: TiffEncoder<W, K>,
+        color: ColorType,
+        strips: &mut dyn FnMut() -> Result<Vec<T>, ImageDataErrors>,
+        tiff_error: &dyn Fn(tiff::TiffError) -> ImageDataErrors
+    ) -> Result<(), ImageDataErrors>
+    where
+        W: Write + Seek,
+        K: TiffKind,
+        T: Sample
+    {
+        match color {
+            ColorType::L8 => self.write::<colortype::Gray8, _, _, _>(&mut encoder, strips, tiff_error),
+            ColorType::Rgb8 => self.write::<colortype::RGB8, _, _, _>(&mut encoder, strips, tiff_error),
+            ColorType::L16 => self.write::<colortype::Gray16, _, _, _>(&mut encoder, strips, tiff_error),
+            ColorType::Rgb16 => self.write::<colortype::RGB16, _, _, _>(&mut encoder, strips, tiff_error),
+            ColorType::Rgba16 => self.write::<colortype::RGBA16, _, _, _>(&mut encoder, strips, tiff_error),
+            ColorType::Rgb32F => self.write::<colortype::RGB32Float, _, _, _>(&mut encoder, strips, tiff_error),
+            ColorType::Rgba32F => self.write::<colortype::RGBA32Float, _, _, _>(&mut encoder, strips, tiff_error),
+            _ => self.write::<colortype::RGBA8, _, _, _>(&mut encoder, strips, tiff_error)
+        }
+    }
+
+    /// Writes every strip and then the tags describing them, with the tiff crate's colour type C
+    fn write<C, W, K, T>(
+        mut self,
+        encoder: &mut TiffEncoder<W, K>,
+        strips: &mut dyn FnMut() -> Result<Vec<T>, ImageDataErrors>,
+        tiff_error: &dyn Fn(tiff::TiffError) -> ImageDataErrors
+    ) -> Result<(), ImageDataErrors>
+    where
+        C: colortype::ColorType,
+        W: Write + Seek,
+        K: TiffKind,
+        T: Sample
+    {
+        let (width, height) = self.dimensions;
+        let row_bytes = (width as usize * C::BITS_PER_SAMPLE.len() * std::mem::size_of::<T>()).max(1);
+
+        let mut directory = encoder.new_directory().map_err(tiff_error)?;
+        let mut offsets = Vec::new();
+        let mut byte_counts = Vec::new();
+
+        let mut rows = 0;
+        while rows < height {
+            let strip = strips()?;
+            let bytes: &[u8] = bytemuck::cast_slice(&strip);
+            rows += (bytes.len() / row_bytes) as u32;
+
+            // PackBits works a row at a time, the rest compress the strip in one go
+            let chunk_length = if self.method == CompressionMethod::PackBits { row_bytes } else { bytes.len().max(1) };
+            let mut compressed = Vec::new();
+            for chunk in bytes.chunks(chunk_length) {
+                self.compressor.write_to(&mut compressed, chunk).map_err(|e| tiff_error(e.into()))?;
+            }
+
+            let offset = directory.write_data(&compressed[..]).map_err(tiff_error)?;
+            offsets.push(K::convert_offset(offset).map_err(tiff_error)?);
+            byte_counts.push(K::convert_offset(compressed.len() as u64).map_err(tiff_error)?);
+        }
+
+        let sample_format: Vec<u16> = C::SAMPLE_FORMAT.iter().map(|format| format.to_u16()).collect();
+        // Sizes are LONGs and the rest SHORTs, the same types the ImageEncoder writes them as
+        for (tag, value) in [(Tag::ImageWidth, width), (Tag::ImageLength, height), (Tag::RowsPerStrip, self.rows_per_strip)] {
+            directory.write_tag(tag, value).map_err(tiff_error)?;
+        }
+        let shorts = [
+            (Tag::Compression, self.method.to_u16()),
+            (Tag::PhotometricInterpretation, C::TIFF_VALUE.to_u16()),
+            (Tag::SamplesPerPixel, C::BITS_PER_SAMPLE.len() as u16),
+            (Tag::ResolutionUnit, ResolutionUnit::None.to_u16())
+        ];
+        for (tag, value) in shorts {
+            directory.write_tag(tag, value).map_err(tiff_error)?;
+        }
+        directory.write_tag(Tag::BitsPerSample, C::BITS_PER_SAMPLE).map_err(tiff_error)?;
+        directory.write_tag(Tag::SampleFormat, &sample_format[..]).map_err(tiff_error)?;
+        directory.write_tag(Tag::XResolution, Rational { n: 1, d: 1 }).map_err(tiff_error)?;
+        directory.write_tag(Tag::YResolution, Rational { n: 1, d: 1 }).map_err(tiff_error)?;
+        directory.write_tag(Tag::StripOffsets, K::convert_slice(&offsets)).map_err(tiff_error)?;
+        directory.write_tag(Tag::StripByteCounts, K::convert_slice(&byte_counts)).map_err(tiff_error)?;
+        directory.finish().map_err(tiff_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::DynamicImage;
+    use std::fmt::Debug;
+    use tiff::decoder::{Decoder, DecodingResult};
+
+    /// rgba samples with only the channels given, every pixel different
+    fn samples<T: Sample>(width: u32, height: u32, channels: Channels) -> Vec<T> {
+        (0..width * height)
+            .flat_map(|index| {
+                let value = |channel: u32| T::from_unit(((index * 7 + channel * 29) % 101) as f32 / 100.0);
+                let (red, green, blue) = if channels.has_color() { (value(0), value(1), value(2)) } else { (value(0), value(0), value(0)) };
+                [red, green, blue, if channels.has_alpha() { value(3) } else { T::OPAQUE }]
+            })
+            .collect()
+    }
+
+    /// Writes the samples a strip at a time, reads the file back and checks nothing changed on the way
+    fn assert_round_trips<T: Sample + PartialEq + Debug>(format: ImageFormat, channels: Channels, tiff_compression: TiffCompression) {
+        let (width, height, rows_per_strip) = (29, 10, 4);
+        let expected = samples::<T>(width, height, channels);
+        let name = format!("{:?}-{:?}-{:?}-{:?}", T::PRECISION, channels, tiff_compression, format);
+        let path = std::env::temp_dir().join(format!("image-combiner-{}-{}.{}", std::process::id(), name, format.extensions_str()[0]));
+        let path = path.to_string_lossy().into_owned();
+
+        let options = EncoderOptions { tiff_compression, ..EncoderOptions::default() };
+        let mut strips = expected.chunks(width as usize * rows_per_strip as usize * 4).map(<[T]>::to_vec);
+        save_strips::<T, _>(&path, format, (width, height), channels, rows_per_strip, &options, || Ok(strips.next().unwrap())).unwrap();
+
+        // The image crate can't read floating point TIFFs, the tiff crate can
+        let actual: Vec<T> = if T::PRECISION == Precision::F32 {
+            let mut decoder = Decoder::new(File::open(&path).unwrap()).unwrap();
+            let DecodingResult::F32(decoded) = decoder.read_image().unwrap() else { panic!("{} isn't floating point", name) };
+            let channels = decoded.len() / (width * height) as usize;
+            decoded.chunks(channels).flat_map(|pixel| [pixel[0], pixel[1], pixel[2], pixel.get(3).copied().unwrap_or(1.0)]).map(bytemuck::cast).collect()
+        } else {
+            T::rgba(&image::open(&path).unwrap()).into_owned()
+        };
+        let _ = std::fs::remove_file(&path);
+        assert!(actual == expected, "{} didn't round trip", name);
+    }
+
+    fn assert_all_channels_round_trip<T: Sample + PartialEq + Debug>(format: ImageFormat, tiff_compression: TiffCompression) {
+        for channels in [Channels::L, Channels::La, Channels::Rgb, Channels::Rgba] {
+            assert_round_trips::<T>(format, channels, tiff_compression);
+        }
+    }
+
+    #[test]
+    fn png_strips_round_trip() {
+        assert_all_channels_round_trip::<u8>(ImageFormat::Png, TiffCompression::None);
+        assert_all_channels_round_trip::<u16>(ImageFormat::Png, TiffCompression::None);
+    }
+
+    #[test]
+    fn tiff_strips_round_trip() {
+        for compression in [TiffCompression::None, TiffCompression::Lzw, TiffCompression::Deflate, TiffCompression::Packbits] {
+            assert_all_channels_round_trip::<u8>(ImageFormat::Tiff, compression);
+            assert_all_channels_round_trip::<u16>(ImageFormat::Tiff, compression);
+            assert_all_channels_round_trip::<f32>(ImageFormat::Tiff, compression);
+        }
+    }
+
+    #[test]
+    fn inputs_are_saved_with_the_fewest_channels() {
+        let rgba = DynamicImage::ImageRgba8(image::RgbaImage::from_raw(3, 2, samples::<u8>(3, 2, Channels::Rgba)).unwrap());
+        let inputs = [rgba.to_luma8().into(), rgba.to_luma_alpha8().into(), rgba.to_rgb8().into(), rgba.clone()];
+        let round_trip = |format: ImageFormat, input: &DynamicImage| {
+            let image = DynamicFloatingImage::from_image(input, Precision::U8, Channels::of(std::slice::from_ref(input)), String::new());
+            let mut encoded = Vec::new();
+            encode(&image, format, &EncoderOptions::default(), &mut encoded).unwrap();
+            image::load_from_memory_with_format(&encoded, format).unwrap().color()
+        };
+
+        let saved: Vec<_> = inputs.iter().map(|input| round_trip(ImageFormat::Png, input)).collect();
+        assert_eq!(saved, [ColorType::L8, ColorType::La8, ColorType::Rgb8, ColorType::Rgba8]);
+        let saved: Vec<_> = inputs.iter().map(|input| round_trip(ImageFormat::Tiff, input)).collect();
+        assert_eq!(saved, [ColorType::L8, ColorType::Rgba8, ColorType::Rgb8, ColorType::Rgba8]);
+
+        // ICOs are only ever read back as rgba, PNM has no alpha so it gets flattened
+        let saved: Vec<_> = inputs.iter().map(|input| round_trip(ImageFormat::Ico, input)).collect();
+        assert_eq!(saved, [ColorType::Rgba8; 4]);
+        let saved: Vec<_> = inputs.iter().map(|input| round_trip(ImageFormat::Pnm, input)).collect();
+        assert_eq!(saved, [ColorType::L8, ColorType::L8, ColorType::Rgb8, ColorType::Rgb8]);
+    }
+
+    #[test]
+    fn flattening_transparency_is_handed_back() {
+        let options = EncoderOptions::default();
+        let opaque = samples::<u8>(3, 2, Channels::Rgb);
+        let transparent = samples::<u8>(3, 2, Channels::Rgba);
+
+        assert_eq!(prepare_pixels(&transparent, 3, Channels::Rgba, ImageFormat::Png, &options).2, None);
+        assert_eq!(prepare_pixels(&opaque, 3, Channels::Rgb, ImageFormat::Jpeg, &options).2, None);
+        assert_eq!(
+            prepare_pixels(&transparent, 3, Channels::Rgba, ImageFormat::Jpeg, &options).2,
+            Some(Warning::TransparencyFlattened(ImageFormat::Jpeg, options.background))
+        );
+        assert_eq!(
+            Warning::TransparencyFlattened(ImageFormat::Jpeg, options.background).to_string(),
+            "JPEG can't store transparency, see through pixels are laid on top of the background (#ffffff)"
+        );
+    }
+
+    #[test]
+    fn output_format_comes_from_the_flag_then_the_extension() {
+        let png = EncoderOptions::default();
+        let jpeg = EncoderOptions { format: Some(ImageFormat::Jpeg), ..EncoderOptions::default() };
+
+        // --format wins over everything, even an extension it doesn't match
+        assert_eq!(output_format("out.png", &jpeg, ImageFormat::Tiff).unwrap(), ImageFormat::Jpeg);
+        assert_eq!(output_format(STDIO_PATH, &jpeg, ImageFormat::Tiff).unwrap(), ImageFormat::Jpeg);
+        assert_eq!(output_format("out.PNG", &png, ImageFormat::Tiff).unwrap(), ImageFormat::Png);
+        assert_eq!(output_format("out/combined", &png, ImageFormat::Tiff).unwrap(), ImageFormat::Tiff);
+
+        for path in ["out.xyz", "out.pngg", STDIO_PATH] {
+            assert!(matches!(output_format(path, &png, ImageFormat::Tiff), Err(ImageDataErrors::MissingOutputFormat(_))), "{}", path);
+        }
+    }
+
+    #[test]
+    fn backgrounds_are_written_the_way_they_are_parsed() {
+        for value in ["#ffffff", "#10203040", "checkerboard:8"] {
+            assert_eq!(value.parse::<Background>().unwrap().to_string(), value);
+        }
+        assert_eq!("checkerboard".parse::<Background>().unwrap().to_string(), "checkerboard:8");
+    }
+}
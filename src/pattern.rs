@@ -0,0 +1,454 @@
+use crate::limits::DecodeLimits;
+use crate::{find_image_from_path_with_limits, ImageDataErrors};
+use image::GrayImage;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Width and height of the blue noise mask, it gets repeated across the output
+const BLUE_NOISE_SIZE: usize = 64;
+
+/// Decides which of the images each pixel of the output is taken from when alternating.
+/// Picked on the command line as `name` or `name:settings`, e.g. `checkerboard:8` or `tiles:3x2`.
+/// The noise patterns (random, bayer and blue-noise) share the pixels out using a weight per image
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// Every other pixel in reading order, carrying on from the end of one row to the start of the next.
+    /// That makes it depend on the width: two images with an even width come out as vertical stripes
+    Pixels,
+    /// Horizontal bands, each the given number of pixels tall
+    Rows(u32),
+    /// Vertical bands, each the given number of pixels wide
+    Columns(u32),
+    /// Checkerboard with cells of the given width and height. Each cell takes the image after the one to
+    /// its left and above it, so with two images it's a true checkerboard and with three or more
+    /// the images run in diagonal stripes going down to the left
+    Checkerboard(u32, u32),
+    /// Splits the image into a grid of the given number of columns and rows,
+    /// each tile taken from the next image in turn
+    Tiles(u32, u32),
+    /// A greyscale image repeated across the output, darker pixels pick earlier images
+    Bitmap(GrayImage),
+    /// White noise, each pixel picked at random from the seed
+    Random(u64),
+    /// Ordered dither using a Bayer matrix of the given size (2, 4, 8 or 16)
+    Bayer(u32),
+    /// Blue noise mask made from the seed, random looking but without clumps
+    BlueNoise(u64, Vec<f32>)
+}
+
+/// Single pixel checkerboard, every pixel's neighbours come from the other images whatever the size of the output
+impl Default for Pattern {
+    fn default() -> Self {
+        Pattern::Checkerboard(1, 1)
+    }
+}
+
+impl Pattern {
+    /// Seed the pattern was made from, if it is a random one.
+    /// Passing it back in (e.g. `random:SEED`) gives exactly the same output again
+    pub fn seed(&self) -> Option<u64> {
+        match self {
+            Pattern::Random(seed) | Pattern::BlueNoise(seed, _) => Some(*seed),
+            _ => None
+        }
+    }
+
+    /// Whether the weights change anything, only the noise patterns share the pixels out by weight
+    pub fn uses_weights(&self) -> bool {
+        matches!(self, Pattern::Random(_) | Pattern::Bayer(_) | Pattern::BlueNoise(..))
+    }
+
+    /// Index of the image the pixel at (x, y) comes from, there is one weight per image.
+    /// Dimensions are the width and height of the output
+    pub fn source(&self, x: u32, y: u32, dimensions: (u32, u32), weights: &[f32]) -> usize {
+        let (width, height) = dimensions;
+        let sources = weights.len();
+        // u64 so that large images can't overflow when cells are counted up
+        let cell = match self {
+            Pattern::Pixels => y as u64 * width as u64 + x as u64,
+            Pattern::Rows(size) => (y / size) as u64,
+            Pattern::Columns(size) => (x / size) as u64,
+            Pattern::Checkerboard(cell_width, cell_height) => (x / cell_width) as u64 + (y / cell_height) as u64,
+            Pattern::Tiles(columns, rows) => {
+                // Spread the tiles evenly, so the last row and column don't end up as slivers
+                let column = x as u64 * *columns as u64 / width as u64;
+                let row = y as u64 * *rows as u64 / height as u64;
+                row * *columns as u64 + column
+            },
+            Pattern::Bitmap(bitmap) => {
+                let value = bitmap.get_pixel(x % bitmap.width(), y % bitmap.height()).0[0];
+                // Split 0 - 255 into as many even ranges as there are sources
+                return value as usize * sources / 256;
+            },
+            // The noise patterns give a threshold from 0.0 - 1.0 which is shared out by weight
+            Pattern::Random(seed) => return pick_weighted(hash_to_unit(*seed, x, y), weights),
+            Pattern::Bayer(size) => return pick_weighted(bayer_threshold(x, y, *size), weights),
+            Pattern::BlueNoise(_, mask) => {
+                let index = (y as usize % BLUE_NOISE_SIZE) * BLUE_NOISE_SIZE + x as usize % BLUE_NOISE_SIZE;
+                return pick_weighted(mask[index], weights);
+            }
+        };
+
+        (cell % sources as u64) as usize
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = ImageDataErrors;
+
+    // A bitmap is held to the default decode limits, the same as any other image read without any given
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Pattern::parse_with_limits(value, &DecodeLimits::default())
+    }
+}
+
+impl Pattern {
+    /// Parses a pattern the same as from_str, with a bitmap held to the limits given.
+    /// The bitmap is read like any input, so its format comes from its contents
+    pub fn parse_with_limits(value: &str, limits: &DecodeLimits) -> Result<Self, ImageDataErrors> {
+        let invalid = || ImageDataErrors::InvalidPattern(value.to_string());
+        // Settings come after a colon, a name on its own uses the defaults
+        let (name, settings) = match value.split_once(':') {
+            Some((name, settings)) => (name, Some(settings)),
+            None => (value, None)
+        };
+
+        match (name, settings) {
+            ("pixels", None) => Ok(Pattern::Pixels),
+            ("rows", settings) => Ok(Pattern::Rows(parse_size(settings.unwrap_or("1")).ok_or_else(invalid)?)),
+            ("columns", settings) => Ok(Pattern::Columns(parse_size(settings.unwrap_or("1")).ok_or_else(invalid)?)),
+            ("checkerboard", settings) => {
+                let settings = settings.unwrap_or("1");
+                // A single number is a square cell, otherwise WIDTHxHEIGHT
+                let (width, height) = match parse_dimensions(settings) {
+                    Some(dimensions) => dimensions,
+                    None => {
+                        let size = parse_size(settings).ok_or_else(invalid)?;
+                        (size, size)
+                    }
+                };
+                Ok(Pattern::Checkerboard(width, height))
+            },
+            ("tiles", Some(settings)) => {
+                let (columns, rows) = parse_dimensions(settings).ok_or_else(invalid)?;
+                Ok(Pattern::Tiles(columns, rows))
+            },
+            ("bitmap", Some(path)) => {
+                let (bitmap, _) = find_image_from_path_with_limits(path.to_string(), limits)?;
+                Ok(Pattern::Bitmap(bitmap.to_luma8()))
+            },
+            ("random", settings) => Ok(Pattern::Random(parse_seed(settings).ok_or_else(invalid)?)),
+            ("bayer", settings) => match settings.unwrap_or("4").parse() {
+                Ok(size) if [2, 4, 8, 16].contains(&size) => Ok(Pattern::Bayer(size)),
+                _ => Err(invalid())
+            },
+            ("blue-noise", settings) => {
+                let seed = parse_seed(settings).ok_or_else(invalid)?;
+                Ok(Pattern::BlueNoise(seed, blue_noise_mask(seed)))
+            },
+            _ => Err(invalid())
+        }
+    }
+}
+
+/// Parses a size in pixels, which has to be at least 1
+fn parse_size(value: &str) -> Option<u32> {
+    value.parse().ok().filter(|size| *size > 0)
+}
+
+/// Parses a WIDTHxHEIGHT pair, both have to be at least 1
+fn parse_dimensions(value: &str) -> Option<(u32, u32)> {
+    let (width, height) = value.split_once('x')?;
+    Some((parse_size(width)?, parse_size(height)?))
+}
+
+/// Parses the seed for a random pattern, when none is given a new one is made from the clock
+fn parse_seed(value: Option<&str>) -> Option<u64> {
+    match value {
+        Some(seed) => seed.parse().ok(),
+        None => Some(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_nanos() as u64)
+                .unwrap_or_default()
+        )
+    }
+}
+
+/// Picks the image a threshold from 0.0 - 1.0 lands on,
+/// each image gets a share of the range as big as its weight
+fn pick_weighted(threshold: f32, weights: &[f32]) -> usize {
+    let total: f32 = weights.iter().sum();
+    let mut cumulative = 0.0;
+
+    for (index, weight) in weights.iter().enumerate() {
+        cumulative += weight / total;
+        if threshold < cumulative {
+            return index;
+        }
+    }
+    // Rounding can leave the very top of the range uncovered, it belongs to the last image
+    weights.len() - 1
+}
+
+/// SplitMix64, scrambles a number so that nearby inputs give unrelated outputs
+fn split_mix(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Random value from 0.0 - 1.0 for the pixel at (x, y).
+/// Worked out from the position rather than a running generator, so the order pixels are visited in doesn't matter
+fn hash_to_unit(seed: u64, x: u32, y: u32) -> f32 {
+    let hash = split_mix(seed ^ split_mix(((y as u64) << 32) | x as u64));
+    // Top 24 bits fit exactly in an f32
+    (hash >> 40) as f32 / (1u64 << 24) as f32
+}
+
+/// Threshold from 0.0 - 1.0 of the Bayer matrix of the given size at (x, y).
+/// Each bit of x and y picks a quadrant, the lowest bits being the most significant
+fn bayer_threshold(x: u32, y: u32, size: u32) -> f32 {
+    let levels = size.trailing_zeros();
+    let mut value = 0;
+
+    for bit in 0..levels {
+        let x_bit = (x >> bit) & 1;
+        let y_bit = (y >> bit) & 1;
+        let shift = 2 * (levels - 1 - bit);
+        value |= ((x_bit ^ y_bit) << (shift + 1)) | (y_bit << shift);
+    }
+
+    // Centre each level in its slot, so no pixel sits right on 0.0
+    (value as f32 + 0.5) / (size * size) as f32
+}
+
+/// Makes a tileable blue noise mask with the void and cluster method, values from 0.0 - 1.0.
+/// Each pixel gets ranked by the order it would be filled in, always filling the emptiest spot next
+fn blue_noise_mask(seed: u64) -> Vec<f32> {
+    let size = BLUE_NOISE_SIZE;
+    let count = size * size;
+
+    // Gaussian falloff for every offset, wrapping round the edges so the mask tiles
+    let sigma: f32 = 1.5;
+    let mut kernel = vec![0.0f32; count];
+    for dy in 0..size {
+        for dx in 0..size {
+            let wx = dx.min(size - dx) as f32;
+            let wy = dy.min(size - dy) as f32;
+            kernel[dy * size + dx] = (-(wx * wx + wy * wy) / (2.0 * sigma * sigma)).exp();
+        }
+    }
+
+    // How crowded each pixel is by the pixels that are switched on
+    let toggle = |energy: &mut [f32], index: usize, sign: f32| {
+        let (ix, iy) = (index % size, index / size);
+        for y in 0..size {
+            for x in 0..size {
+                let offset = ((y + size - iy) % size) * size + (x + size - ix) % size;
+                energy[y * size + x] += sign * kernel[offset];
+            }
+        }
+    };
+    // The tightest cluster is the most crowded pixel that's on, the largest void the least crowded that's off
+    let tightest_cluster = |on: &[bool], energy: &[f32]| {
+        (0..count).filter(|&i| on[i]).max_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap()
+    };
+    let largest_void = |on: &[bool], energy: &[f32]| {
+        (0..count).filter(|&i| !on[i]).min_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap()
+    };
+
+    // Start with roughly a tenth of the pixels switched on at random
+    let mut on = vec![false; count];
+    let mut energy = vec![0.0f32; count];
+    let mut state = seed;
+    let initial = count / 10;
+    let mut placed = 0;
+    while placed < initial {
+        state = split_mix(state);
+        let index = (state % count as u64) as usize;
+        if !on[index] {
+            on[index] = true;
+            toggle(&mut energy, index, 1.0);
+            placed += 1;
+        }
+    }
+
+    // Even the starting pixels out, moving the most clumped one into the biggest gap until it stops moving
+    loop {
+        let cluster = tightest_cluster(&on, &energy);
+        on[cluster] = false;
+        toggle(&mut energy, cluster, -1.0);
+
+        let void = largest_void(&on, &energy);
+        on[void] = true;
+        toggle(&mut energy, void, 1.0);
+
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0usize; count];
+
+    // The starting pixels are ranked by taking them away again, most clumped first
+    let mut removing = on.clone();
+    let mut removing_energy = energy.clone();
+    for rank in (0..initial).rev() {
+        let cluster = tightest_cluster(&removing, &removing_energy);
+        removing[cluster] = false;
+        toggle(&mut removing_energy, cluster, -1.0);
+        ranks[cluster] = rank;
+    }
+
+    // Every other pixel is ranked by filling in the biggest gap left
+    for rank in initial..count {
+        let void = largest_void(&on, &energy);
+        on[void] = true;
+        toggle(&mut energy, void, 1.0);
+        ranks[void] = rank;
+    }
+
+    ranks.into_iter().map(|rank| (rank as f32 + 0.5) / count as f32).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::Limit;
+
+    #[test]
+    fn default_pattern_does_not_depend_on_the_width() {
+        let weights = [1.0; 2];
+        for width in [4, 5] {
+            let sources: Vec<usize> = (0..4)
+                .flat_map(|y| (0..4).map(move |x| Pattern::default().source(x, y, (width, 4), &weights)))
+                .collect();
+            assert_eq!(sources, [0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0], "{} wide", width);
+        }
+    }
+
+    #[test]
+    fn checkerboard_runs_diagonally_with_three_images() {
+        let weights = [1.0; 3];
+        let source = |x, y| "checkerboard".parse::<Pattern>().unwrap().source(x, y, (6, 6), &weights);
+        for (x, y) in [(0, 0), (1, 0), (2, 3), (5, 5)] {
+            // One step right or down is the next image, so a step down and to the left is the same one again
+            assert_eq!(source(x + 1, y), (source(x, y) + 1) % 3);
+            assert_eq!(source(x, y + 1), (source(x, y) + 1) % 3);
+            assert_eq!(source(x + 1, y), source(x, y + 1));
+        }
+    }
+
+    /// Which source every pixel of an image of the given size comes from, row by row
+    fn sources(pattern: &Pattern, dimensions: (u32, u32), weights: &[f32]) -> Vec<Vec<usize>> {
+        (0..dimensions.1).map(|y| (0..dimensions.0).map(|x| pattern.source(x, y, dimensions, weights)).collect()).collect()
+    }
+
+    #[test]
+    fn rows_and_columns_make_bands() {
+        let weights = [1.0; 3];
+        let rows = sources(&"rows:2".parse().unwrap(), (3, 8), &weights);
+        assert_eq!(rows.iter().map(|row| row[0]).collect::<Vec<_>>(), [0, 0, 1, 1, 2, 2, 0, 0]);
+        assert!(rows.iter().all(|row| row.iter().all(|source| *source == row[0])));
+
+        let columns = sources(&"columns:3".parse().unwrap(), (8, 3), &[1.0; 2]);
+        for row in columns {
+            assert_eq!(row, [0, 0, 0, 1, 1, 1, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn tiles_are_spread_evenly() {
+        // 10 doesn't split into 3 evenly, the extra pixel goes to the first column rather than a sliver at the end
+        let tiles = sources(&"tiles:3x2".parse().unwrap(), (10, 5), &[1.0; 6]);
+        let top = [0, 0, 0, 0, 1, 1, 1, 2, 2, 2];
+        let bottom = [3, 3, 3, 3, 4, 4, 4, 5, 5, 5];
+        assert_eq!(tiles, [top, top, top, bottom, bottom]);
+
+        // With fewer images than tiles they go round again
+        let tiles = sources(&"tiles:3x2".parse().unwrap(), (10, 5), &[1.0; 4]);
+        assert_eq!(tiles[4], [3, 3, 3, 3, 0, 0, 0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn bitmaps_split_the_greys_evenly() {
+        let bitmap = GrayImage::from_raw(6, 1, vec![0, 85, 86, 127, 128, 255]).unwrap();
+        let pattern = Pattern::Bitmap(bitmap);
+        assert_eq!(sources(&pattern, (6, 1), &[1.0; 2]), [[0, 0, 0, 0, 1, 1]]);
+        assert_eq!(sources(&pattern, (6, 1), &[1.0; 3]), [[0, 0, 1, 1, 1, 2]]);
+        // It's repeated across bigger outputs
+        assert_eq!(sources(&pattern, (8, 2), &[1.0; 2]), [[0, 0, 0, 0, 1, 1, 0, 0]; 2]);
+    }
+
+    #[test]
+    fn seeded_noise_is_the_same_every_time() {
+        let weights = [1.0; 3];
+        for name in ["random", "blue-noise"] {
+            let pattern: Pattern = format!("{}:42", name).parse().unwrap();
+            assert_eq!(pattern.seed(), Some(42));
+            let first = sources(&pattern, (70, 70), &weights);
+            assert_eq!(first, sources(&format!("{}:42", name).parse().unwrap(), (70, 70), &weights), "{}", name);
+            assert_ne!(first, sources(&format!("{}:43", name).parse().unwrap(), (70, 70), &weights), "{}", name);
+        }
+    }
+
+    #[test]
+    fn weights_set_each_sources_share() {
+        let share = |pattern: &str, weights: &[f32]| {
+            let mut counts = vec![0; weights.len()];
+            for source in sources(&pattern.parse().unwrap(), (64, 64), weights).into_iter().flatten() {
+                counts[source] += 1;
+            }
+            counts
+        };
+
+        // Every value of the blue noise mask is used once, so the shares come out exact
+        assert_eq!(share("blue-noise:5", &[1.0, 1.0, 2.0]), [1024, 1024, 2048]);
+        assert_eq!(share("bayer:4", &[1.0, 3.0]), [1024, 3072]);
+        assert_eq!(share("blue-noise:5", &[0.0, 1.0]), [0, 4096]);
+        // White noise is only near enough
+        let random = share("random:5", &[1.0, 3.0]);
+        assert!((random[1] as f32 / 4096.0 - 0.75).abs() < 0.03, "{:?}", random);
+    }
+
+    #[test]
+    fn bayer_matrices_are_laid_out_in_order() {
+        // With as many images as levels, each pixel's source is its place in the matrix
+        let bayer = |size: u32| sources(&Pattern::Bayer(size), (size * 2, size * 2), &vec![1.0; (size * size) as usize]);
+        let two = bayer(2);
+        assert_eq!(two[..2], [[0, 2, 0, 2], [3, 1, 3, 1]]);
+        assert_eq!(two[..2], two[2..]);
+
+        let four = bayer(4);
+        let matrix = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+        for (y, row) in matrix.iter().enumerate() {
+            assert_eq!(four[y][..4], row[..]);
+            // The matrix repeats across and down
+            assert_eq!(four[y][4..], row[..]);
+            assert_eq!(four[y + 4][..4], row[..]);
+        }
+
+        // Every level is used once
+        let mut eight: Vec<_> = bayer(8).into_iter().take(8).flat_map(|row| row.into_iter().take(8)).collect();
+        eight.sort();
+        assert_eq!(eight, (0..64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn bitmaps_are_held_to_the_decode_limits() {
+        // Named .jpg but a PNG inside, it's read by its contents like any input
+        let path = std::env::temp_dir().join(format!("image-combiner-{}-bitmap.jpg", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        image::GrayImage::from_fn(8, 4, |x, _| image::Luma([x as u8 * 32])).save_with_format(&path, image::ImageFormat::Png).unwrap();
+
+        let value = format!("bitmap:{}", path);
+        let limited = Pattern::parse_with_limits(&value, &DecodeLimits { max_width: Some(4), ..DecodeLimits::default() });
+        let parsed = value.parse::<Pattern>();
+        let _ = std::fs::remove_file(&path);
+
+        assert!(matches!(limited, Err(ImageDataErrors::ExceedsDecodeLimit(_, (8, 4), Limit::Width(4)))));
+        assert!(matches!(parsed, Ok(Pattern::Bitmap(bitmap)) if bitmap.dimensions() == (8, 4)));
+    }
+}
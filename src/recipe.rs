@@ -0,0 +1,181 @@
+use crate::args::{EncoderArgs, SizeArgs};
+use image_combiner::{ImageDataErrors, STDIO_PATH};
+use serde::{de, Deserialize, Deserializer};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Extensions a saved recipe can have, tried in this order when looking one up by name
+const RECIPE_EXTENSIONS: [&str; 2] = ["toml", "json"];
+
+/// Settings for a combine job saved in a TOML or JSON file, so the same job can be run again.
+/// Everything is optional and the names match the command line flags e.g.
+///
+/// ```toml
+/// inputs = ["left.png", "right.png"]
+/// output = "combined.png"
+/// mode = "multiply"
+/// opacity = 0.8
+///
+/// [resize]
+/// size = "1920x1080"
+/// fit = "pad:#000000"
+/// quality = "best"
+///
+/// [encoder]
+/// jpeg-quality = 90
+/// progressive = true
+/// ```
+///
+/// Relative inputs and output in a recipe file are relative to the folder the file is in, so it runs
+/// the same from anywhere. Recipes saved in the recipes folder are meant to be reused on different images,
+/// so theirs are relative to wherever it's run from, as are bitmap patterns in either
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Recipe {
+    pub inputs: Vec<String>,
+    pub output: Option<String>,
+    pub mode: Option<String>,
+    pub opacity: Option<f32>,
+    // Parsed along with the flag, once the decode limits for a bitmap are known
+    pub pattern: Option<String>,
+    pub weights: Option<Vec<f32>>,
+    pub resize: SizeArgs,
+    pub encoder: EncoderArgs
+}
+
+impl Recipe {
+    /// Loads a recipe from a path, or by name from the recipes folder in the user's config directory
+    /// e.g. `thumbnails` loads `~/.config/image-combiner/recipes/thumbnails.toml` on Linux
+    pub fn load(name: &str) -> Result<Self, ImageDataErrors> {
+        let (path, saved) = find_recipe(name).ok_or_else(|| ImageDataErrors::RecipeNotFound(name.to_string()))?;
+        let path_name = path.to_string_lossy().into_owned();
+        let contents = std::fs::read_to_string(&path).map_err(|e| ImageDataErrors::UnableToReadRecipe(path_name.clone(), e))?;
+
+        // JSON only when the extension says so, anything else is read as TOML
+        let recipe = if path.extension().is_some_and(|extension| extension == "json") {
+            serde_json::from_str(&contents).map_err(|e| e.to_string())
+        } else {
+            toml::from_str(&contents).map_err(|e| e.to_string())
+        };
+        let mut recipe: Recipe = recipe.map_err(|message| ImageDataErrors::InvalidRecipe(path_name, message.trim().to_string()))?;
+
+        if let Some(dir) = path.parent().filter(|_| !saved) {
+            recipe.inputs = recipe.inputs.into_iter().map(|input| relative_to(dir, input)).collect();
+            recipe.output = recipe.output.map(|output| relative_to(dir, output));
+        }
+        Ok(recipe)
+    }
+}
+
+/// The path as seen from the folder given, absolute paths and `-` (standard input or output) are left alone
+fn relative_to(dir: &Path, path: String) -> String {
+    if path == STDIO_PATH || Path::new(&path).is_absolute() {
+        return path;
+    }
+    dir.join(path).to_string_lossy().into_owned()
+}
+
+/// Folder saved recipes are looked up in
+pub fn recipe_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|config| config.join("image-combiner").join("recipes"))
+}
+
+/// A path that exists is used as it is, otherwise it's the name of a recipe in the recipe folder.
+/// Along with the path comes whether it's one of the saved ones from the recipe folder
+fn find_recipe(name: &str) -> Option<(PathBuf, bool)> {
+    let path = Path::new(name);
+    if path.is_file() {
+        return Some((path.to_path_buf(), false));
+    }
+
+    let dir = recipe_dir()?;
+    RECIPE_EXTENSIONS
+        .iter()
+        .map(|extension| dir.join(format!("{}.{}", name, extension)))
+        .find(|path| path.is_file())
+        .map(|path| (path, true))
+}
+
+/// Reads an optional value with its FromStr, so recipes use the same text as the command line
+pub fn parse<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display
+{
+    let value: Option<String> = Option::deserialize(deserializer)?;
+    value.map(|value| value.parse().map_err(de::Error::custom)).transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image_combiner::size::{Fit, Target};
+
+    /// Writes a recipe into a fresh folder, returning its path
+    fn write_recipe(name: &str, contents: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("image-combiner-{}-recipe-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Loads the recipe and cleans up after it
+    fn load(name: &str, contents: &str) -> (Result<Recipe, ImageDataErrors>, PathBuf) {
+        let path = write_recipe(name, contents);
+        let recipe = Recipe::load(&path.to_string_lossy());
+        let dir = path.parent().unwrap().to_path_buf();
+        let _ = std::fs::remove_dir_all(&dir);
+        (recipe, dir)
+    }
+
+    #[test]
+    fn loads_toml_and_json() {
+        let toml = "mode = \"multiply\"\nopacity = 0.5\npattern = \"rows:2\"\n\n[resize]\nsize = \"64x32\"\nfit = \"pad:#ff0000\"\n\n[encoder]\njpeg-quality = 90\n";
+        let json = r##"{"mode": "multiply", "opacity": 0.5, "pattern": "rows:2", "resize": {"size": "64x32", "fit": "pad:#ff0000"}, "encoder": {"jpeg-quality": 90}}"##;
+
+        for (name, contents) in [("r.toml", toml), ("r.json", json)] {
+            let recipe = load(name, contents).0.unwrap();
+            assert_eq!(recipe.mode.as_deref(), Some("multiply"), "{}", name);
+            assert_eq!(recipe.opacity, Some(0.5));
+            assert_eq!(recipe.pattern.as_deref(), Some("rows:2"));
+            assert_eq!(recipe.resize.size, Some(Target::Fixed(64, 32)));
+            assert_eq!(recipe.resize.fit, Some(Fit::Pad(image::Rgba([255, 0, 0, 255]))));
+            assert_eq!(recipe.encoder.jpeg_quality, Some(90));
+            assert!(recipe.inputs.is_empty() && recipe.output.is_none());
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_bad_values() {
+        // A typo would otherwise be silently ignored
+        for (name, contents) in [
+            ("typo.toml", "mdoe = \"multiply\"\n"),
+            ("nested.toml", "[resize]\nszie = \"64x32\"\n"),
+            ("typo.json", r#"{"opactiy": 0.5}"#),
+            ("value.toml", "[resize]\nfit = \"squash\"\n")
+        ] {
+            let (recipe, dir) = load(name, contents);
+            match recipe {
+                Err(ImageDataErrors::InvalidRecipe(path, _)) => assert_eq!(Path::new(&path), dir.join(name)),
+                other => panic!("{} should be invalid, got {:?}", name, other.map(|_| ()))
+            }
+        }
+        assert!(matches!(Recipe::load("/no/such/recipe.toml"), Err(ImageDataErrors::RecipeNotFound(_))));
+    }
+
+    #[test]
+    fn paths_are_relative_to_the_recipe() {
+        let absolute = if cfg!(windows) { "C:\\\\images\\\\b.png" } else { "/images/b.png" };
+        let contents = format!("inputs = [\"a.png\", \"sub/*.png\", \"{}\", \"-\"]\noutput = \"out/combined.png\"\n", absolute);
+        let (recipe, dir) = load("relative.toml", &contents);
+        let recipe = recipe.unwrap();
+
+        let beside = |path: &str| dir.join(path).to_string_lossy().into_owned();
+        assert_eq!(recipe.inputs, [beside("a.png"), beside("sub/*.png"), absolute.to_string(), STDIO_PATH.to_string()]);
+        assert_eq!(recipe.output, Some(beside("out/combined.png")));
+    }
+}
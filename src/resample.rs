@@ -0,0 +1,462 @@
+use crate::floating_image::Precision;
+use crate::ImageDataErrors;
+use image::{imageops::FilterType, ColorType, DynamicImage, ImageBuffer, Rgba32FImage};
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+/// Filter used when scaling an image up or down
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+    /// Whole number scaling with nearest neighbour so pixels stay square and sharp,
+    /// only the last bit of scaling left over gets smoothed
+    PixelArt
+}
+
+/// How images get resized, the filter and whether it's done in linear light
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Resampling {
+    pub filter: Filter,
+    // Resizing on sRGB values directly darkens edges and fine detail,
+    // converting to linear light first averages the actual brightness instead
+    pub linear: bool
+}
+
+impl Default for Resampling {
+    fn default() -> Self {
+        Resampling { filter: Filter::Triangle, linear: false }
+    }
+}
+
+impl Resampling {
+    /// Settings for a named quality preset: fast, balanced, best or pixel-art
+    pub fn preset(name: &str) -> Result<Self, ImageDataErrors> {
+        match name {
+            "fast" => Ok(Resampling { filter: Filter::Nearest, linear: false }),
+            "balanced" => Ok(Resampling::default()),
+            "best" => Ok(Resampling { filter: Filter::Lanczos3, linear: true }),
+            "pixel-art" => Ok(Resampling { filter: Filter::PixelArt, linear: false }),
+            _ => Err(ImageDataErrors::InvalidFilter(name.to_string()))
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = ImageDataErrors;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "nearest" => Ok(Filter::Nearest),
+            "triangle" => Ok(Filter::Triangle),
+            "catmull-rom" => Ok(Filter::CatmullRom),
+            "gaussian" => Ok(Filter::Gaussian),
+            "lanczos3" => Ok(Filter::Lanczos3),
+            "pixel-art" => Ok(Filter::PixelArt),
+            _ => Err(ImageDataErrors::InvalidFilter(value.to_string()))
+        }
+    }
+}
+
+/// Resizes the image to exactly width x height with the resampling settings given
+pub fn resize(image: &DynamicImage, width: u32, height: u32, resampling: &Resampling) -> DynamicImage {
+    // Floating point images (EXR, HDR) are already linear light, and can be brighter than white
+    if Precision::of_color(image.color()) == Precision::F32 {
+        return resize_hdr(image, width, height, resampling);
+    }
+
+    if resampling.linear {
+        let linear = to_linear(image);
+        let resized = resize_with_filter(&linear, width, height, resampling.filter);
+        from_linear(resized)
+    } else {
+        resize_with_filter(image, width, height, resampling.filter)
+    }
+}
+
+fn resize_with_filter(image: &DynamicImage, width: u32, height: u32, filter: Filter) -> DynamicImage {
+    match filter_type(filter) {
+        Some(filter_type) => image.resize_exact(width, height, filter_type),
+        None => resize_pixel_art(image, width, height)
+    }
+}
+
+/// The image crate's filter for one of ours, pixel art doesn't have one as it's made out of two of them
+fn filter_type(filter: Filter) -> Option<FilterType> {
+    match filter {
+        Filter::Nearest => Some(FilterType::Nearest),
+        Filter::Triangle => Some(FilterType::Triangle),
+        Filter::CatmullRom => Some(FilterType::CatmullRom),
+        Filter::Gaussian => Some(FilterType::Gaussian),
+        Filter::Lanczos3 => Some(FilterType::Lanczos3),
+        Filter::PixelArt => None
+    }
+}
+
+/// The image crate's filters clamp floating point samples to 0.0 - 1.0, which would cut off every highlight.
+/// The colour gets scaled down so the brightest value is 1.0, resized, then scaled back up again.
+/// It's already linear, so linear only decides whether the colour is premultiplied by alpha
+fn resize_hdr(image: &DynamicImage, width: u32, height: u32, resampling: &Resampling) -> DynamicImage {
+    let mut scaled = image.to_rgba32f();
+    let peak = scaled.pixels().flat_map(|pixel| [pixel.0[0], pixel.0[1], pixel.0[2]]).fold(1.0, f32::max);
+    for pixel in scaled.pixels_mut() {
+        let alpha = if resampling.linear { pixel.0[3] } else { 1.0 };
+        for channel in 0..3 {
+            pixel.0[channel] *= alpha / peak;
+        }
+    }
+
+    let mut resized = resize_with_filter(&DynamicImage::ImageRgba32F(scaled), width, height, resampling.filter).into_rgba32f();
+    for pixel in resized.pixels_mut() {
+        let alpha = if resampling.linear { pixel.0[3] } else { 1.0 };
+        for channel in 0..3 {
+            pixel.0[channel] = if alpha > 0.0 { pixel.0[channel] / alpha * peak } else { 0.0 };
+        }
+    }
+    DynamicImage::ImageRgba32F(resized)
+}
+
+/// Scales up by the smallest whole number that reaches the target with nearest neighbour,
+/// then smooths it down the rest of the way. Whole number scales come out perfectly sharp
+fn resize_pixel_art(image: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let mut resized = image.clone();
+    for (size, filter_type) in pixel_art_steps(image.width(), image.height(), width, height) {
+        resized = resized.resize_exact(size.0, size.1, filter_type);
+    }
+    resized
+}
+
+/// The resizes pixel art scaling is made of, the size each one goes to and its filter
+fn pixel_art_steps(image_width: u32, image_height: u32, width: u32, height: u32) -> Vec<((u32, u32), FilterType)> {
+    // Scaling down can't keep every pixel, so nearest neighbour at least keeps them crisp
+    if width <= image_width || height <= image_height {
+        return vec![((width, height), FilterType::Nearest)];
+    }
+
+    let factor_x = (width as f64 / image_width as f64).ceil() as u32;
+    let factor_y = (height as f64 / image_height as f64).ceil() as u32;
+    let factor = factor_x.max(factor_y);
+
+    let scaled = (image_width * factor, image_height * factor);
+    if scaled == (width, height) {
+        vec![(scaled, FilterType::Nearest)]
+    } else {
+        vec![(scaled, FilterType::Nearest), ((width, height), FilterType::Triangle)]
+    }
+}
+
+/// Converts an sRGB value from 0.0 - 1.0 to linear light
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Converts a linear light value from 0.0 - 1.0 back to sRGB
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Floating point copy of the image in linear light, with the colour premultiplied by alpha
+/// so see through pixels don't bleed their colour into their neighbours
+pub(crate) fn to_linear(image: &DynamicImage) -> DynamicImage {
+    let mut linear = image.to_rgba32f();
+    for pixel in linear.pixels_mut() {
+        let alpha = pixel.0[3];
+        for channel in 0..3 {
+            pixel.0[channel] = srgb_to_linear(pixel.0[channel]) * alpha;
+        }
+    }
+    DynamicImage::ImageRgba32F(linear)
+}
+
+/// Undoes to_linear, the result stays floating point so no precision is lost before combining
+pub(crate) fn from_linear(image: DynamicImage) -> DynamicImage {
+    let mut srgb: Rgba32FImage = image.into_rgba32f();
+    for pixel in srgb.pixels_mut() {
+        // Filters like Lanczos can overshoot, so everything is pulled back into range
+        let alpha = pixel.0[3].clamp(0.0, 1.0);
+        pixel.0[3] = alpha;
+        for channel in 0..3 {
+            let unpremultiplied = if alpha > 0.0 { pixel.0[channel] / alpha } else { 0.0 };
+            pixel.0[channel] = linear_to_srgb(unpremultiplied.clamp(0.0, 1.0));
+        }
+    }
+    DynamicImage::ImageRgba32F(srgb)
+}
+
+/// Range the samples of a row are kept in while resizing a strip at a time: 255.0 for 8-bit images,
+/// 65535.0 for 16-bit ones and 1.0 for floating point. Whole number samples get rounded after
+/// every resize, the same as the image crate does when it writes them back into the image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Depth {
+    pub max: f32,
+    pub round: bool
+}
+
+impl Depth {
+    /// Depth rows of an image of this colour type are read at. Linear resizing always works in floating point
+    pub(crate) fn of(color: ColorType, linear: bool) -> Self {
+        match Precision::of_color(color) {
+            _ if linear => Depth { max: 1.0, round: false },
+            Precision::U8 => Depth { max: u8::MAX as f32, round: true },
+            Precision::U16 => Depth { max: u16::MAX as f32, round: true },
+            Precision::F32 => Depth { max: 1.0, round: false }
+        }
+    }
+
+    /// The rows of a strip as rgba samples at this depth, read the way the image crate reads them when resizing
+    pub(crate) fn rows(self, strip: &DynamicImage, linear: bool) -> Vec<Vec<f32>> {
+        let width = strip.width() as usize * 4;
+        let samples: Vec<f32> = if linear {
+            to_linear(strip).into_rgba32f().into_raw()
+        } else if self.max == u8::MAX as f32 {
+            strip.to_rgba8().into_raw().into_iter().map(f32::from).collect()
+        } else if self.max == u16::MAX as f32 {
+            strip.to_rgba16().into_raw().into_iter().map(f32::from).collect()
+        } else {
+            strip.to_rgba32f().into_raw()
+        };
+        samples.chunks(width.max(1)).map(<[f32]>::to_vec).collect()
+    }
+
+    /// Puts resized rows back together into an image, undoing the linear light if that's what they're in
+    pub(crate) fn image(self, rows: Vec<Vec<f32>>, width: u32, linear: bool) -> DynamicImage {
+        let height = rows.len() as u32;
+        let samples = rows.into_iter().flatten();
+        let image = if self.max == u8::MAX as f32 {
+            DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, samples.map(|sample| sample as u8).collect()).unwrap())
+        } else if self.max == u16::MAX as f32 {
+            DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, samples.map(|sample| sample as u16).collect()).unwrap())
+        } else {
+            DynamicImage::ImageRgba32F(ImageBuffer::from_raw(width, height, samples.collect()).unwrap())
+        };
+        if linear { from_linear(image) } else { image }
+    }
+}
+
+// The image crate's kernels, copied so resizing a strip at a time does exactly the same sums
+
+fn sinc(t: f32) -> f32 {
+    let a = t * std::f32::consts::PI;
+    if t == 0.0 {
+        1.0
+    } else {
+        a.sin() / a
+    }
+}
+
+fn lanczos(x: f32, t: f32) -> f32 {
+    if x.abs() < t {
+        sinc(x) * sinc(x / t)
+    } else {
+        0.0
+    }
+}
+
+fn bc_cubic_spline(x: f32, b: f32, c: f32) -> f32 {
+    let a = x.abs();
+    let k = if a < 1.0 {
+        (12.0 - 9.0 * b - 6.0 * c) * a.powi(3) + (-18.0 + 12.0 * b + 6.0 * c) * a.powi(2) + (6.0 - 2.0 * b)
+    } else if a < 2.0 {
+        (-b - 6.0 * c) * a.powi(3) + (6.0 * b + 30.0 * c) * a.powi(2) + (-12.0 * b - 48.0 * c) * a + (8.0 * b + 24.0 * c)
+    } else {
+        0.0
+    };
+    k / 6.0
+}
+
+fn gaussian(x: f32, r: f32) -> f32 {
+    ((2.0 * std::f32::consts::PI).sqrt() * r).recip() * (-x.powi(2) / (2.0 * r.powi(2))).exp()
+}
+
+/// Kernel of the filter and how far it reaches either side of the pixel
+fn kernel(filter_type: FilterType) -> (fn(f32) -> f32, f32) {
+    match filter_type {
+        FilterType::Nearest => (|_| 1.0, 0.0),
+        FilterType::Triangle => (|x| if x.abs() < 1.0 { 1.0 - x.abs() } else { 0.0 }, 1.0),
+        FilterType::CatmullRom => (|x| bc_cubic_spline(x, 0.0, 0.5), 2.0),
+        FilterType::Gaussian => (|x| gaussian(x, 0.5), 3.0),
+        FilterType::Lanczos3 => (|x| lanczos(x, 3.0), 3.0)
+    }
+}
+
+/// Which input pixels along one side each output pixel is made from, the first one and how much of each
+struct Taps {
+    first: Vec<u32>,
+    weights: Vec<Vec<f32>>
+}
+
+impl Taps {
+    fn new(input: u32, output: u32, filter_type: FilterType) -> Self {
+        let (kernel, support) = kernel(filter_type);
+        let ratio = input as f32 / output as f32;
+        let sratio = if ratio < 1.0 { 1.0 } else { ratio };
+        let src_support = support * sratio;
+
+        let (mut first, mut weights) = (Vec::new(), Vec::new());
+        for out in 0..output {
+            let center = (out as f32 + 0.5) * ratio;
+            let left = ((center - src_support).floor() as i64).clamp(0, input as i64 - 1) as u32;
+            let right = ((center + src_support).ceil() as i64).clamp(left as i64 + 1, input as i64) as u32;
+            let center = center - 0.5;
+
+            let mut taps = Vec::new();
+            let mut sum = 0.0;
+            for i in left..right {
+                let weight = kernel((i as f32 - center) / sratio);
+                taps.push(weight);
+                sum += weight;
+            }
+            taps.iter_mut().for_each(|weight| *weight /= sum);
+            first.push(left);
+            weights.push(taps);
+        }
+        Taps { first, weights }
+    }
+
+    /// Most input pixels any one output pixel needs
+    fn span(&self) -> usize {
+        self.weights.iter().map(Vec::len).max().unwrap_or(0)
+    }
+}
+
+/// One resize of an image a row at a time, the same two passes as the image crate's resize (down
+/// the columns, then along the rows) so the result is exactly what resizing the whole image gives.
+/// Only the input rows the next output row is made from are kept, in a window rolling down the image
+pub(crate) struct RowResizer {
+    width: u32,
+    depth: Depth,
+    // None when the size doesn't change, the rows are just passed through like the image crate copies them
+    taps: Option<(Taps, Taps)>,
+    window: VecDeque<Vec<f32>>,
+    window_top: u32,
+    next_row: u32
+}
+
+/// Gives the next rows of the input to a resize, as many as asked for
+pub(crate) type PullRows<'a> = dyn FnMut(u32) -> Result<Vec<Vec<f32>>, ImageDataErrors> + 'a;
+
+impl RowResizer {
+    fn new(input: (u32, u32), output: (u32, u32), filter_type: FilterType, depth: Depth) -> Self {
+        let taps = (input != output).then(|| (Taps::new(input.0, output.0, filter_type), Taps::new(input.1, output.1, filter_type)));
+        RowResizer { width: input.0, depth, taps, window: VecDeque::new(), window_top: 0, next_row: 0 }
+    }
+
+    /// The resizes going from the input size to the output one with the filter given, pixel art takes two
+    pub(crate) fn chain(input: (u32, u32), output: (u32, u32), filter: Filter, depth: Depth) -> Vec<RowResizer> {
+        let steps = match filter_type(filter) {
+            Some(filter_type) => vec![(output, filter_type)],
+            None => pixel_art_steps(input.0, input.1, output.0, output.1)
+        };
+        let mut from = input;
+        steps.into_iter().map(|(to, filter_type)| {
+            let resizer = RowResizer::new(from, to, filter_type, depth);
+            from = to;
+            resizer
+        }).collect()
+    }
+
+    /// Bytes the window of input rows takes up at most
+    pub(crate) fn buffer_size(&self) -> u64 {
+        let rows = self.taps.as_ref().map(|(_, vertical)| vertical.span()).unwrap_or(0);
+        rows as u64 * self.width as u64 * 4 * std::mem::size_of::<f32>() as u64
+    }
+
+    /// Resizes the next count rows, pulling in the input rows they need
+    pub(crate) fn next_rows(&mut self, count: u32, pull: &mut PullRows) -> Result<Vec<Vec<f32>>, ImageDataErrors> {
+        let Some((horizontal, vertical)) = &self.taps else {
+            return pull(count);
+        };
+
+        let mut rows = Vec::with_capacity(count as usize);
+        for y in self.next_row..self.next_row + count {
+            let first = vertical.first[y as usize];
+            let weights = &vertical.weights[y as usize];
+            while self.window_top < first && !self.window.is_empty() {
+                self.window.pop_front();
+                self.window_top += 1;
+            }
+            if self.window.is_empty() {
+                // Skipping over rows nothing needs, which only happens with nearest neighbour
+                if self.window_top < first {
+                    pull(first - self.window_top)?;
+                    self.window_top = first;
+                }
+            }
+            let missing = (first + weights.len() as u32).saturating_sub(self.window_top + self.window.len() as u32);
+            if missing > 0 {
+                self.window.extend(pull(missing)?);
+            }
+
+            let mut column_sums = vec![0.0f32; self.width as usize * 4];
+            for (row, weight) in self.window.iter().skip((first - self.window_top) as usize).zip(weights) {
+                for (sum, sample) in column_sums.iter_mut().zip(row) {
+                    *sum += sample * weight;
+                }
+            }
+
+            let mut row = Vec::with_capacity(horizontal.first.len() * 4);
+            for (&first, weights) in horizontal.first.iter().zip(&horizontal.weights) {
+                let mut pixel = [0.0f32; 4];
+                for (i, weight) in weights.iter().enumerate() {
+                    let x = (first as usize + i) * 4;
+                    for channel in 0..4 {
+                        pixel[channel] += column_sums[x + channel] * weight;
+                    }
+                }
+                row.extend(pixel.map(|sample| {
+                    let clamped = if sample < 0.0 { 0.0 } else if sample > self.depth.max { self.depth.max } else { sample };
+                    if self.depth.round { clamped.round() } else { clamped }
+                }));
+            }
+            rows.push(row);
+        }
+        self.next_row += count;
+        Ok(rows)
+    }
+}
+
+/// Runs rows through a chain of resizes, pulling from the input at the start of the chain
+pub(crate) fn pull_through(resizers: &mut [RowResizer], count: u32, pull: &mut PullRows) -> Result<Vec<Vec<f32>>, ImageDataErrors> {
+    match resizers.split_last_mut() {
+        None => pull(count),
+        Some((last, rest)) => last.next_rows(count, &mut |count| pull_through(rest, count, pull))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn hdr_highlights_survive_resizing() {
+        // A flat image four times brighter than white stays that bright at any size, with any filter
+        let image = DynamicImage::ImageRgba32F(Rgba32FImage::from_pixel(8, 8, Rgba([4.0, 2.0, 0.5, 1.0])));
+        for resampling in ["fast", "balanced", "best"].map(|name| Resampling::preset(name).unwrap()) {
+            let resized = resize(&image, 3, 5, &resampling).into_rgba32f();
+            for pixel in resized.pixels() {
+                for (value, expected) in pixel.0.iter().zip([4.0, 2.0, 0.5, 1.0]) {
+                    assert!((value - expected).abs() < 1e-4, "{:?} gave {:?}", resampling, pixel);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn linear_resizing_round_trips_srgb() {
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(4, 4, Rgba([200, 100, 50, 255])));
+        let resized = resize(&image, 2, 2, &Resampling { filter: Filter::Triangle, linear: true }).to_rgba8();
+        assert!(resized.pixels().all(|pixel| *pixel == Rgba([200, 100, 50, 255])));
+    }
+}
@@ -0,0 +1,406 @@
+use crate::resample::{resize, Resampling};
+use crate::ImageDataErrors;
+use image::{imageops, DynamicImage, GenericImageView, Rgba, Rgba32FImage};
+use rayon::prelude::*;
+use std::str::FromStr;
+
+/// The size every image gets brought to before combining
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    /// Size of the image with the fewest pixels
+    Smallest,
+    /// Size of the image with the most pixels
+    Largest,
+    /// An exact width and height
+    Fixed(u32, u32)
+}
+
+/// How an image of a different shape is fitted to the target size
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fit {
+    /// Squash or stretch to the target, ignoring the aspect ratio
+    Stretch,
+    /// Scale until the target is covered, then crop off what hangs over
+    Crop,
+    /// Scale until the image fits inside the target, fill the rest with a colour
+    Pad(Rgba<u8>),
+    /// Scale until the image fits inside the target, on top of a blurred copy of itself filling the rest
+    Letterbox
+}
+
+/// Which part of the image is kept when cropping, or where it sits when padding
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight
+}
+
+/// Everything deciding how the images are made the same size
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SizePolicy {
+    pub target: Target,
+    pub fit: Fit,
+    pub anchor: Anchor,
+    pub resampling: Resampling
+}
+
+impl Default for SizePolicy {
+    // Shrinks to the smallest image like always, but keeps the aspect ratio by cropping the middle out
+    fn default() -> Self {
+        SizePolicy {
+            target: Target::Smallest,
+            fit: Fit::Crop,
+            anchor: Anchor::Center,
+            resampling: Resampling::default()
+        }
+    }
+}
+
+impl Anchor {
+    /// Where the anchor sits along each side, from 0.0 (left/top) to 1.0 (right/bottom)
+    fn factors(self) -> (f32, f32) {
+        match self {
+            Anchor::TopLeft => (0.0, 0.0),
+            Anchor::Top => (0.5, 0.0),
+            Anchor::TopRight => (1.0, 0.0),
+            Anchor::Left => (0.0, 0.5),
+            Anchor::Center => (0.5, 0.5),
+            Anchor::Right => (1.0, 0.5),
+            Anchor::BottomLeft => (0.0, 1.0),
+            Anchor::Bottom => (0.5, 1.0),
+            Anchor::BottomRight => (1.0, 1.0)
+        }
+    }
+
+    /// Offset to place something inside of a space with the given amount of room left over
+    pub(crate) fn offset(self, spare_width: u32, spare_height: u32) -> (u32, u32) {
+        let (x, y) = self.factors();
+        ((spare_width as f32 * x).round() as u32, (spare_height as f32 * y).round() as u32)
+    }
+}
+
+impl FromStr for Target {
+    type Err = ImageDataErrors;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "smallest" => Ok(Target::Smallest),
+            "largest" => Ok(Target::Largest),
+            // Anything else has to be an exact WIDTHxHEIGHT
+            _ => value
+                .split_once('x')
+                .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+                .filter(|(width, height)| *width > 0 && *height > 0)
+                .map(|(width, height)| Target::Fixed(width, height))
+                .ok_or_else(|| ImageDataErrors::InvalidSizePolicy(value.to_string()))
+        }
+    }
+}
+
+impl FromStr for Fit {
+    type Err = ImageDataErrors;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || ImageDataErrors::InvalidSizePolicy(value.to_string());
+
+        match value.split_once(':') {
+            // Padding can be given a colour as `pad:#rrggbb`
+            Some(("pad", color)) => Ok(Fit::Pad(parse_color(color).ok_or_else(invalid)?)),
+            Some(_) => Err(invalid()),
+            None => match value {
+                "stretch" => Ok(Fit::Stretch),
+                "crop" => Ok(Fit::Crop),
+                "pad" => Ok(Fit::Pad(Rgba([0, 0, 0, 255]))),
+                "letterbox" => Ok(Fit::Letterbox),
+                _ => Err(invalid())
+            }
+        }
+    }
+}
+
+impl FromStr for Anchor {
+    type Err = ImageDataErrors;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "top-left" => Ok(Anchor::TopLeft),
+            "top" => Ok(Anchor::Top),
+            "top-right" => Ok(Anchor::TopRight),
+            "left" => Ok(Anchor::Left),
+            "center" => Ok(Anchor::Center),
+            "right" => Ok(Anchor::Right),
+            "bottom-left" => Ok(Anchor::BottomLeft),
+            "bottom" => Ok(Anchor::Bottom),
+            "bottom-right" => Ok(Anchor::BottomRight),
+            _ => Err(ImageDataErrors::InvalidSizePolicy(value.to_string()))
+        }
+    }
+}
+
+/// Parses a hex colour, `#rrggbb` or `#rrggbbaa` (the # is optional)
+pub fn parse_color(value: &str) -> Option<Rgba<u8>> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if !(hex.len() == 6 || hex.len() == 8) || !hex.is_ascii() {
+        return None;
+    }
+
+    let channel = |index: usize| u8::from_str_radix(&hex[index..index + 2], 16).ok();
+    let alpha = if hex.len() == 8 { channel(6)? } else { 255 };
+
+    Some(Rgba([channel(0)?, channel(2)?, channel(4)?, alpha]))
+}
+
+/// Get's the smallest of the dimensions provided, returns height and width of type u32 of it
+fn get_smallest_dimensions(dimensions: &[(u32, u32)]) -> (u32, u32) {
+    // Compare by number of pixels in each image to get size,
+    // u64 so that two large dimensions multiplied together can't overflow
+    *dimensions
+        .iter()
+        .min_by_key(|(width, height)| *width as u64 * *height as u64)
+        .expect("at least one image to get the dimensions of")
+}
+
+/// Get's the largest of the dimensions provided, the same way as get_smallest_dimensions
+fn get_largest_dimensions(dimensions: &[(u32, u32)]) -> (u32, u32) {
+    *dimensions
+        .iter()
+        .max_by_key(|(width, height)| *width as u64 * *height as u64)
+        .expect("at least one image to get the dimensions of")
+}
+
+/// The size images with the dimensions given get brought to
+pub fn target_dimensions(dimensions: &[(u32, u32)], target: Target) -> (u32, u32) {
+    match target {
+        Target::Smallest => get_smallest_dimensions(dimensions),
+        Target::Largest => get_largest_dimensions(dimensions),
+        Target::Fixed(width, height) => (width, height)
+    }
+}
+
+/// Brings every picture to the size the policy picks, returns all images in the same order they were given
+pub fn standardize_size(images: Vec<DynamicImage>, policy: &SizePolicy) -> Vec<DynamicImage> {
+
+    // Dimensions method comes from image crate
+    let dimensions: Vec<(u32, u32)> = images.iter().map(|image| image.dimensions()).collect();
+    let (width, height) = target_dimensions(&dimensions, policy.target);
+
+    // Each image is resized on its own thread, collect keeps them in order
+    images
+        .into_par_iter()
+        .map(|image| {
+            // Images that already have the right dimensions are left alone, the rest get fitted
+            if image.dimensions() == (width, height) {
+                image
+            } else {
+                fit_image(image, width, height, policy)
+            }
+        })
+        .collect()
+}
+
+/// Fits a single image to the width and height given
+fn fit_image(image: DynamicImage, width: u32, height: u32, policy: &SizePolicy) -> DynamicImage {
+    match policy.fit {
+        Fit::Stretch => resize(&image, width, height, &policy.resampling),
+        Fit::Crop => cover(&image, width, height, policy.anchor, &policy.resampling),
+        Fit::Pad(color) => {
+            let background = Rgba32FImage::from_pixel(width, height, pad_color(color));
+            place_inside(&image, background, policy.anchor, &policy.resampling)
+        },
+        Fit::Letterbox => {
+            let quick = Resampling::default();
+            let (small_width, small_height) = letterbox_size(width, height);
+            let small = cover(&image, small_width, small_height, Anchor::Center, &quick);
+            let background = resize(&small.blur(LETTERBOX_BLUR), width, height, &quick).to_rgba32f();
+            place_inside(&image, background, policy.anchor, &policy.resampling)
+        }
+    }
+}
+
+/// The padding colour in floating point, the same as the background it's laid on
+pub(crate) fn pad_color(color: Rgba<u8>) -> Rgba<f32> {
+    Rgba(color.0.map(|channel| channel as f32 / 255.0))
+}
+
+// Blurring a small copy and scaling it back up is much quicker than blurring at full size,
+// and looks the same once it's that blurry. Being a blur, the quick default filter is plenty
+pub(crate) const LETTERBOX_BLUR: f32 = 4.0;
+
+/// Size of the small copy blurred for a letterbox background
+pub(crate) fn letterbox_size(width: u32, height: u32) -> (u32, u32) {
+    ((width / 8).max(1), (height / 8).max(1))
+}
+
+/// Size an image of the dimensions given gets scaled to so it covers the whole of width x height
+pub(crate) fn cover_size(dimensions: (u32, u32), width: u32, height: u32) -> (u32, u32) {
+    let scale = f64::max(width as f64 / dimensions.0 as f64, height as f64 / dimensions.1 as f64);
+    // Rounding up, so the scaled image is never a pixel short of the target
+    (((dimensions.0 as f64 * scale).ceil() as u32).max(width), ((dimensions.1 as f64 * scale).ceil() as u32).max(height))
+}
+
+/// Size an image of the dimensions given gets scaled to so it fits inside of width x height
+pub(crate) fn inside_size(dimensions: (u32, u32), width: u32, height: u32) -> (u32, u32) {
+    let scale = f64::min(width as f64 / dimensions.0 as f64, height as f64 / dimensions.1 as f64);
+    (
+        ((dimensions.0 as f64 * scale).round() as u32).clamp(1, width),
+        ((dimensions.1 as f64 * scale).round() as u32).clamp(1, height)
+    )
+}
+
+/// Scales the image to cover the whole of width x height, then crops it down around the anchor
+fn cover(image: &DynamicImage, width: u32, height: u32, anchor: Anchor, resampling: &Resampling) -> DynamicImage {
+    let (scaled_width, scaled_height) = cover_size(image.dimensions(), width, height);
+
+    let scaled = resize(image, scaled_width, scaled_height, resampling);
+    let (x, y) = anchor.offset(scaled_width - width, scaled_height - height);
+    scaled.crop_imm(x, y, width, height)
+}
+
+/// Scales the image to fit inside of the background, and lays it on top at the anchor.
+/// The background is floating point so 16-bit and HDR images don't lose anything on the way
+fn place_inside(image: &DynamicImage, mut background: Rgba32FImage, anchor: Anchor, resampling: &Resampling) -> DynamicImage {
+    let (width, height) = background.dimensions();
+    let (scaled_width, scaled_height) = inside_size(image.dimensions(), width, height);
+
+    let scaled = resize(image, scaled_width, scaled_height, resampling);
+    let (x, y) = anchor.offset(width - scaled_width, height - scaled_height);
+    imageops::overlay(&mut background, &scaled.to_rgba32f(), x as i64, y as i64);
+
+    DynamicImage::ImageRgba32F(background)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbaImage;
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+    const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+
+    /// 200x100, red on the left half and blue on the right
+    fn halves() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(200, 100, |x, _| if x < 100 { RED } else { BLUE }))
+    }
+
+    /// Fits the image into 100x100 with the fit given, nearest neighbour so colours stay exact
+    fn fit_square(image: DynamicImage, fit: Fit, anchor: Anchor) -> RgbaImage {
+        let resampling = Resampling { filter: crate::resample::Filter::Nearest, linear: false };
+        let policy = SizePolicy { target: Target::Fixed(100, 100), fit, anchor, resampling };
+        let fitted = standardize_size(vec![image], &policy).remove(0);
+        assert_eq!(fitted.dimensions(), (100, 100));
+        fitted.to_rgba8()
+    }
+
+    #[test]
+    fn cover_and_inside_sizes_keep_the_aspect_ratio() {
+        assert_eq!(cover_size((200, 100), 100, 100), (200, 100));
+        assert_eq!(cover_size((100, 300), 60, 60), (60, 180));
+        assert_eq!(inside_size((200, 100), 100, 100), (100, 50));
+        assert_eq!(inside_size((100, 300), 60, 60), (20, 60));
+        // Never smaller than the target when covering, never a zero sized side when fitting inside
+        assert_eq!(cover_size((3, 7), 10, 10), (10, 24));
+        assert_eq!(inside_size((1000, 1), 10, 10), (10, 1));
+    }
+
+    #[test]
+    fn crop_keeps_the_middle_without_squashing() {
+        let cropped = fit_square(halves(), Fit::Crop, Anchor::Center);
+        // Half of each colour, the quarters on either side are cropped off
+        assert_eq!(*cropped.get_pixel(49, 50), RED);
+        assert_eq!(*cropped.get_pixel(50, 50), BLUE);
+
+        // Anchored left, it's all red
+        let cropped = fit_square(halves(), Fit::Crop, Anchor::Left);
+        assert!(cropped.pixels().all(|pixel| *pixel == RED));
+    }
+
+    #[test]
+    fn pad_and_letterbox_fit_the_whole_image_inside() {
+        let white = Rgba([255, 255, 255, 255]);
+        let padded = fit_square(halves(), Fit::Pad(white), Anchor::Center);
+        // Scaled to 100x50 in the middle, with the colour above and below
+        assert_eq!(*padded.get_pixel(10, 24), white);
+        assert_eq!(*padded.get_pixel(10, 25), RED);
+        assert_eq!(*padded.get_pixel(90, 74), BLUE);
+        assert_eq!(*padded.get_pixel(90, 75), white);
+
+        let padded = fit_square(halves(), Fit::Pad(white), Anchor::Bottom);
+        assert_eq!(*padded.get_pixel(10, 49), white);
+        assert_eq!(*padded.get_pixel(10, 50), RED);
+
+        // Letterbox puts it in the same place, on a blurry version of itself instead of a colour
+        let letterboxed = fit_square(halves(), Fit::Letterbox, Anchor::Center);
+        assert_eq!(*letterboxed.get_pixel(10, 25), RED);
+        assert_eq!(*letterboxed.get_pixel(90, 74), BLUE);
+        let background = letterboxed.get_pixel(10, 5);
+        assert!(background[0] > background[2], "the red side's background should be mostly red, got {:?}", background);
+    }
+
+    #[test]
+    fn anchors_place_at_every_edge_and_corner() {
+        let offsets = [
+            (Anchor::TopLeft, (0, 0)),
+            (Anchor::Top, (5, 0)),
+            (Anchor::TopRight, (10, 0)),
+            (Anchor::Left, (0, 10)),
+            (Anchor::Center, (5, 10)),
+            (Anchor::Right, (10, 10)),
+            (Anchor::BottomLeft, (0, 20)),
+            (Anchor::Bottom, (5, 20)),
+            (Anchor::BottomRight, (10, 20))
+        ];
+        for (anchor, offset) in offsets {
+            assert_eq!(anchor.offset(10, 20), offset, "{:?}", anchor);
+        }
+        // An odd amount left over rounds to the nearest pixel
+        assert_eq!(Anchor::Center.offset(5, 3), (3, 2));
+    }
+
+    #[test]
+    fn smallest_and_largest_go_by_pixel_count() {
+        // The widest and tallest images aren't the biggest
+        let dimensions = [(100, 1), (20, 20), (1, 150), (30, 10)];
+        assert_eq!(target_dimensions(&dimensions, Target::Smallest), (100, 1));
+        assert_eq!(target_dimensions(&dimensions, Target::Largest), (20, 20));
+        assert_eq!(target_dimensions(&dimensions, Target::Fixed(7, 9)), (7, 9));
+    }
+
+    #[test]
+    fn parses_the_policy_settings() {
+        assert_eq!("smallest".parse::<Target>().unwrap(), Target::Smallest);
+        assert_eq!("largest".parse::<Target>().unwrap(), Target::Largest);
+        assert_eq!("640x480".parse::<Target>().unwrap(), Target::Fixed(640, 480));
+        for value in ["0x10", "10x0", "10x", "x10", "10", "tiny", "-5x5"] {
+            assert!(value.parse::<Target>().is_err(), "{}", value);
+        }
+
+        assert_eq!("stretch".parse::<Fit>().unwrap(), Fit::Stretch);
+        assert_eq!("crop".parse::<Fit>().unwrap(), Fit::Crop);
+        assert_eq!("letterbox".parse::<Fit>().unwrap(), Fit::Letterbox);
+        assert_eq!("pad".parse::<Fit>().unwrap(), Fit::Pad(Rgba([0, 0, 0, 255])));
+        assert_eq!("pad:#ff000080".parse::<Fit>().unwrap(), Fit::Pad(Rgba([255, 0, 0, 128])));
+        for value in ["pad:#zz", "pad:", "crop:#000000", "squash"] {
+            assert!(value.parse::<Fit>().is_err(), "{}", value);
+        }
+
+        assert_eq!("bottom-right".parse::<Anchor>().unwrap(), Anchor::BottomRight);
+        assert!("middle".parse::<Anchor>().is_err());
+    }
+
+    #[test]
+    fn parses_hex_colours() {
+        assert_eq!(parse_color("#102030"), Some(Rgba([16, 32, 48, 255])));
+        assert_eq!(parse_color("102030"), Some(Rgba([16, 32, 48, 255])));
+        assert_eq!(parse_color("#10203040"), Some(Rgba([16, 32, 48, 64])));
+        for value in ["#zz", "#zzzzzz", "#12345", "#1234567", "", "#ééé"] {
+            assert_eq!(parse_color(value), None, "{}", value);
+        }
+    }
+}
@@ -0,0 +1,641 @@
+use crate::combiner::Combiner;
+use crate::floating_image::{Channels, Precision, Sample};
+use crate::output::{self, EncoderOptions};
+use crate::resample::{pull_through, Depth, Resampling, RowResizer};
+use crate::size::{self, target_dimensions, Anchor, Fit, SizePolicy, Target};
+use crate::{open_image, ImageDataErrors};
+use image::error::{DecodingError, ImageFormatHint};
+use image::{imageops, ColorType, DynamicImage, ImageBuffer, ImageError, ImageFormat, Rgba, Rgba32FImage};
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{self, BufReader};
+use tiff::decoder::{ChunkType, Decoder, DecodingResult};
+use tiff::tags::Tag;
+
+/// Parses a size in bytes, either a plain number or one ending in K, M, G or T (powers of 1024) e.g. `512M`, `1.5G`
+pub fn parse_memory_size(value: &str) -> Result<u64, ImageDataErrors> {
+    let invalid = || ImageDataErrors::InvalidMemoryBudget(value.to_string());
+
+    // 4G, 4GB and 4GiB all mean the same
+    let upper = value.trim().to_ascii_uppercase();
+    let number = upper.trim_end_matches('B').trim_end_matches('I');
+    let (number, scale) = match number.char_indices().last() {
+        Some((index, unit)) if unit.is_ascii_alphabetic() => {
+            let power = "KMGT".find(unit).ok_or_else(invalid)? as i32 + 1;
+            (&number[..index], 1024f64.powi(power))
+        },
+        _ => (number, 1.0)
+    };
+
+    let bytes = number.trim().parse::<f64>().map_err(|_| invalid())? * scale;
+    if bytes.is_finite() && bytes >= 1.0 {
+        Ok(bytes as u64)
+    } else {
+        Err(invalid())
+    }
+}
+
+/// Writes a number of bytes out the way people read them e.g. `1.5 GiB`
+pub fn format_size(bytes: u64) -> String {
+    let units = ["bytes", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 { format!("{} bytes", bytes) } else { format!("{:.1} {}", size, units[unit]) }
+}
+
+/// Reads an image a strip of rows at a time, from the top down. Only formats that store their rows
+/// in order can be read like this: PNGs that aren't interlaced and TIFFs. Images that were already
+/// decoded can be too, the strips are copied out of them. Any of them can be resized on the way with fit
+pub struct StripReader {
+    path: String,
+    width: u32,
+    height: u32,
+    color: ColorType,
+    source: Source
+}
+
+enum Source {
+    // The decoders are boxed as they're a lot bigger than a DynamicImage
+    Png(Box<png::Reader<BufReader<File>>>),
+    Tiff(Box<TiffRows>),
+    Image(DynamicImage, u32),
+    Fitted(Box<Fitted>)
+}
+
+/// A TIFF's strips or tiles, decoded a row of them at a time. Rows that were decoded
+/// but haven't been read yet wait in pending, as native endian bytes
+struct TiffRows {
+    decoder: Decoder<BufReader<File>>,
+    next_chunk_row: u32,
+    pending: Vec<u8>
+}
+
+impl StripReader {
+    /// Reads the header of the image at path, Ok(None) if its format can't be read a strip at a time
+    pub fn open(path: &str) -> Result<Option<Self>, ImageDataErrors> {
+        // The format comes from the file's contents, the same as when it's decoded whole
+        let reader = open_image(path)?;
+        let format = match reader.format() {
+            Some(format @ (ImageFormat::Png | ImageFormat::Tiff)) => format,
+            _ => return Ok(None)
+        };
+
+        let opened = match format {
+            ImageFormat::Png => open_png(reader.into_inner()).map_err(|e| decoding_error(path, format, e))?,
+            _ => open_tiff(reader.into_inner()).map_err(|e| decoding_error(path, format, e))?
+        };
+
+        Ok(opened.map(|(width, height, color, source)| StripReader { path: path.to_string(), width, height, color, source }))
+    }
+
+    /// Reads strips out of an image that's already been decoded
+    pub fn from_image(image: DynamicImage) -> Self {
+        StripReader {
+            path: String::new(),
+            width: image.width(),
+            height: image.height(),
+            color: image.color(),
+            source: Source::Image(image, 0)
+        }
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Colour type of the image. Fitting keeps the bit depth, but padding can add colour or alpha to it
+    pub fn color(&self) -> ColorType {
+        self.color
+    }
+
+    /// The format being read, images that were already decoded don't have one
+    pub fn format(&self) -> Option<ImageFormat> {
+        match &self.source {
+            Source::Png(_) => Some(ImageFormat::Png),
+            Source::Tiff(_) => Some(ImageFormat::Tiff),
+            Source::Image(..) => None,
+            Source::Fitted(fitted) => fitted.source.format()
+        }
+    }
+
+    /// Bytes kept around between strips, TIFFs decode a whole row of their strips or tiles at once
+    /// and resizing keeps the rows the next one is made from
+    pub fn buffer_size(&self) -> u64 {
+        match &self.source {
+            Source::Tiff(rows) => rows.decoder.chunk_dimensions().1 as u64 * self.width as u64 * self.color.bytes_per_pixel() as u64,
+            Source::Fitted(fitted) => fitted.buffer_size(),
+            _ => 0
+        }
+    }
+
+    /// Bytes each row read takes up. Fitted images that get placed on a background come out as floating point rgba
+    pub fn row_size(&self) -> u64 {
+        let bytes_per_pixel = match &self.source {
+            Source::Fitted(fitted) if fitted.background.is_some() => 4 * std::mem::size_of::<f32>() as u64,
+            _ => self.color.bytes_per_pixel() as u64
+        };
+        self.width as u64 * bytes_per_pixel
+    }
+
+    /// Fits the image to width x height as it's read, the same way standardize_size fits whole images and with
+    /// exactly the same result. Letterboxing reads the image through once first, to make the blurred background.
+    /// Floating point images can't be fitted like this, they're resized relative to their brightest pixel
+    pub fn fit(self, width: u32, height: u32, policy: &SizePolicy) -> Result<StripReader, ImageDataErrors> {
+        let dimensions = self.dimensions();
+        let (scaled, background, added) = match policy.fit {
+            Fit::Stretch => ((width, height), None, None),
+            Fit::Crop => (size::cover_size(dimensions, width, height), None, None),
+            Fit::Pad(color) => {
+                let scaled = size::inside_size(dimensions, width, height);
+                // The padding only shows when the image doesn't fill the whole target
+                let added = (scaled != (width, height)).then_some(color);
+                (scaled, Some(Background::Color(size::pad_color(color))), added)
+            },
+            Fit::Letterbox => {
+                let background = self.letterbox_background(width, height)?;
+                (size::inside_size(dimensions, width, height), Some(Background::Image(Box::new(background))), None)
+            }
+        };
+        let offset = match background {
+            Some(_) => policy.anchor.offset(width - scaled.0, height - scaled.1),
+            None => policy.anchor.offset(scaled.0 - width, scaled.1 - height)
+        };
+
+        let color = match added {
+            Some(Rgba([red, green, blue, alpha])) => {
+                let channels = Channels::new(self.color.has_color() || red != green || green != blue, self.color.has_alpha() || alpha < u8::MAX);
+                let color_type = match Precision::of_color(self.color) {
+                    Precision::U16 => u16::color_type(channels.count()),
+                    _ => u8::color_type(channels.count())
+                };
+                color_type.unwrap_or(self.color)
+            },
+            None => self.color
+        };
+
+        let linear = policy.resampling.linear;
+        let depth = Depth::of(self.color, linear);
+        let resizers = RowResizer::chain(dimensions, scaled, policy.resampling.filter, depth);
+        let fitted = Fitted { source: self, resizers, depth, linear, scaled, offset, background, scaled_rows: 0, top: 0 };
+        Ok(StripReader { path: fitted.source.path.clone(), width, height, color, source: Source::Fitted(Box::new(fitted)) })
+    }
+
+    /// The blurred copy of the image letterboxing puts behind it, made by reading the image through once
+    /// at an eighth of the size, then scaled back up to width x height as it's read
+    fn letterbox_background(&self, width: u32, height: u32) -> Result<StripReader, ImageDataErrors> {
+        let quick = Resampling::default();
+        let (small_width, small_height) = size::letterbox_size(width, height);
+        let cover = SizePolicy { target: Target::Fixed(small_width, small_height), fit: Fit::Crop, anchor: Anchor::Center, resampling: quick };
+        let small = self.reopen()?.fit(small_width, small_height, &cover)?.read(small_height)?;
+
+        let stretch = SizePolicy { fit: Fit::Stretch, ..cover };
+        StripReader::from_image(small.blur(size::LETTERBOX_BLUR)).fit(width, height, &stretch)
+    }
+
+    /// A new reader for the same image, starting from the top again
+    fn reopen(&self) -> Result<StripReader, ImageDataErrors> {
+        match &self.source {
+            Source::Image(image, _) => Ok(StripReader::from_image(image.clone())),
+            Source::Fitted(fitted) => fitted.source.reopen(),
+            _ => StripReader::open(&self.path)?.ok_or_else(|| ImageDataErrors::UnableToFormatImage(self.path.clone()))
+        }
+    }
+
+    /// Decodes the next count rows
+    pub fn read(&mut self, count: u32) -> Result<DynamicImage, ImageDataErrors> {
+        let row_length = self.width as usize * self.color.bytes_per_pixel() as usize;
+        let length = row_length * count as usize;
+        let format = self.format().unwrap_or(ImageFormat::Tiff);
+
+        let bytes = match &mut self.source {
+            Source::Png(reader) => read_png_rows(reader, count, length, self.color).map_err(|e| decoding_error(&self.path, format, e))?,
+            Source::Tiff(rows) => {
+                while rows.pending.len() < length {
+                    rows.read_chunk_row(self.width, row_length).map_err(|e| decoding_error(&self.path, format, e))?;
+                }
+                let rest = rows.pending.split_off(length);
+                std::mem::replace(&mut rows.pending, rest)
+            },
+            Source::Image(image, top) => {
+                let strip = image.crop_imm(0, *top, self.width, count);
+                *top += count;
+                return Ok(strip);
+            },
+            Source::Fitted(fitted) => return fitted.read(self.width, count)
+        };
+
+        image_from_bytes(self.width, count, self.color, bytes)
+            .ok_or_else(|| decoding_error(&self.path, format, "the decoded rows don't match the image's size"))
+    }
+}
+
+/// An image being fitted to the target size as it's read: resized a few rows at a time,
+/// then cropped or laid on top of its background
+struct Fitted {
+    source: StripReader,
+    resizers: Vec<RowResizer>,
+    depth: Depth,
+    linear: bool,
+    scaled: (u32, u32),
+    // Without a background the resized image gets cropped, starting offset into it.
+    // With one it's laid on top, offset into the background
+    offset: (u32, u32),
+    background: Option<Background>,
+    // Rows of the resized image and of the fitted one read so far
+    scaled_rows: u32,
+    top: u32
+}
+
+enum Background {
+    Color(Rgba<f32>),
+    Image(Box<StripReader>)
+}
+
+impl Fitted {
+    fn buffer_size(&self) -> u64 {
+        let background = match &self.background {
+            Some(Background::Image(background)) => background.buffer_size(),
+            _ => 0
+        };
+        self.source.buffer_size() + self.resizers.iter().map(RowResizer::buffer_size).sum::<u64>() + background
+    }
+
+    /// Resizes the next count rows of the image
+    fn scaled(&mut self, count: u32) -> Result<DynamicImage, ImageDataErrors> {
+        let Fitted { source, resizers, depth, linear, .. } = self;
+        let (depth, linear) = (*depth, *linear);
+        let rows = pull_through(resizers, count, &mut |count| Ok(depth.rows(&source.read(count)?, linear)))?;
+        self.scaled_rows += count;
+        Ok(depth.image(rows, self.scaled.0, linear))
+    }
+
+    fn read(&mut self, width: u32, count: u32) -> Result<DynamicImage, ImageDataErrors> {
+        let (top, (x, y)) = (self.top, self.offset);
+        self.top += count;
+
+        let mut strip = match &mut self.background {
+            Some(Background::Color(color)) => Rgba32FImage::from_pixel(width, count, *color),
+            Some(Background::Image(background)) => background.read(count)?.into_rgba32f(),
+            None => {
+                // Rows above the crop are still resized, the ones below them are made from them
+                if self.scaled_rows < y {
+                    self.scaled(y - self.scaled_rows)?;
+                }
+                return Ok(self.scaled(count)?.crop_imm(x, 0, width, count));
+            }
+        };
+
+        // Only the rows of the image that land in this strip
+        let first = top.max(y);
+        let last = (top + count).min(y + self.scaled.1);
+        if first < last {
+            let scaled = self.scaled(last - first)?;
+            imageops::overlay(&mut strip, &scaled.to_rgba32f(), x as i64, (first - top) as i64);
+        }
+        Ok(DynamicImage::ImageRgba32F(strip))
+    }
+}
+
+/// Wraps up an error from the png or tiff crate the same way the image crate would have
+fn decoding_error<E>(path: &str, format: ImageFormat, error: E) -> ImageDataErrors
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>
+{
+    ImageDataErrors::UnableToDecodeImage(path.to_string(), ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(format), error)))
+}
+
+type Opened = Option<(u32, u32, ColorType, Source)>;
+
+/// Reads a PNG's header, expanding palettes and low bit depths the same as the image crate does
+fn open_png(file: BufReader<File>) -> Result<Opened, png::DecodingError> {
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let reader = decoder.read_info()?;
+
+    // Interlaced PNGs store their rows out of order
+    if reader.info().interlaced {
+        return Ok(None);
+    }
+
+    let (width, height) = (reader.info().width, reader.info().height);
+    let color = match reader.output_color_type() {
+        (png::ColorType::Grayscale, png::BitDepth::Eight) => ColorType::L8,
+        (png::ColorType::GrayscaleAlpha, png::BitDepth::Eight) => ColorType::La8,
+        (png::ColorType::Rgb, png::BitDepth::Eight) => ColorType::Rgb8,
+        (png::ColorType::Rgba, png::BitDepth::Eight) => ColorType::Rgba8,
+        (png::ColorType::Grayscale, png::BitDepth::Sixteen) => ColorType::L16,
+        (png::ColorType::GrayscaleAlpha, png::BitDepth::Sixteen) => ColorType::La16,
+        (png::ColorType::Rgb, png::BitDepth::Sixteen) => ColorType::Rgb16,
+        (png::ColorType::Rgba, png::BitDepth::Sixteen) => ColorType::Rgba16,
+        _ => return Ok(None)
+    };
+
+    Ok(Some((width, height, color, Source::Png(Box::new(reader)))))
+}
+
+/// Reads a TIFF's header. Only the colour types the image crate can decode are read in strips
+fn open_tiff(file: BufReader<File>) -> Result<Opened, tiff::TiffError> {
+    let mut decoder = Decoder::new(file)?;
+    let (width, height) = decoder.dimensions()?;
+
+    let color = match decoder.colortype()? {
+        tiff::ColorType::Gray(8) => ColorType::L8,
+        tiff::ColorType::GrayA(8) => ColorType::La8,
+        tiff::ColorType::RGB(8) => ColorType::Rgb8,
+        tiff::ColorType::RGBA(8) => ColorType::Rgba8,
+        tiff::ColorType::Gray(16) => ColorType::L16,
+        tiff::ColorType::GrayA(16) => ColorType::La16,
+        tiff::ColorType::RGB(16) => ColorType::Rgb16,
+        tiff::ColorType::RGBA(16) => ColorType::Rgba16,
+        _ => return Ok(None)
+    };
+
+    // Planar TIFFs keep each channel apart, so a row of pixels is spread all over the file
+    if decoder.find_tag_unsigned::<u16>(Tag::PlanarConfiguration)? == Some(2) {
+        return Ok(None);
+    }
+
+    Ok(Some((width, height, color, Source::Tiff(Box::new(TiffRows { decoder, next_chunk_row: 0, pending: Vec::new() })))))
+}
+
+/// Reads the next count rows of a PNG, as native endian bytes
+fn read_png_rows(reader: &mut png::Reader<BufReader<File>>, count: u32, length: usize, color: ColorType) -> Result<Vec<u8>, png::DecodingError> {
+    let mut bytes = Vec::with_capacity(length);
+    for _ in 0..count {
+        match reader.next_row()? {
+            Some(row) => bytes.extend_from_slice(row.data()),
+            None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+        }
+    }
+
+    // PNGs store 16-bit samples big endian
+    if color.bytes_per_pixel() > color.channel_count() {
+        for sample in bytes.chunks_exact_mut(2) {
+            let value = u16::from_be_bytes([sample[0], sample[1]]);
+            sample.copy_from_slice(&value.to_ne_bytes());
+        }
+    }
+    Ok(bytes)
+}
+
+impl TiffRows {
+    /// Decodes the next strip, or the next row of tiles, onto the end of pending
+    fn read_chunk_row(&mut self, width: u32, row_length: usize) -> Result<(), tiff::TiffError> {
+        let (chunk_width, _) = self.decoder.chunk_dimensions();
+        let across = match self.decoder.get_chunk_type() {
+            ChunkType::Strip => 1,
+            ChunkType::Tile => width.div_ceil(chunk_width.max(1))
+        };
+
+        // Each tile only covers part of every row, so the rows get stitched back together from them
+        let first = self.next_chunk_row * across;
+        let tiles = (first..first + across)
+            .map(|index| Ok((self.decoder.chunk_data_dimensions(index), samples_to_bytes(self.decoder.read_chunk(index)?)?)))
+            .collect::<Result<Vec<_>, tiff::TiffError>>()?;
+        self.next_chunk_row += 1;
+
+        let rows = tiles.first().map(|((_, rows), _)| *rows).unwrap_or(0);
+        let bytes_per_pixel = row_length / width.max(1) as usize;
+        for row in 0..rows as usize {
+            for ((tile_width, _), bytes) in &tiles {
+                let tile_row = *tile_width as usize * bytes_per_pixel;
+                self.pending.extend_from_slice(&bytes[row * tile_row..(row + 1) * tile_row]);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Samples the tiff crate decoded as native endian bytes
+fn samples_to_bytes(result: DecodingResult) -> Result<Vec<u8>, tiff::TiffError> {
+    match result {
+        DecodingResult::U8(samples) => Ok(samples),
+        DecodingResult::U16(samples) => Ok(bytemuck::cast_slice(&samples).to_vec()),
+        _ => Err(tiff::TiffError::UnsupportedError(tiff::TiffUnsupportedError::UnknownInterpretation))
+    }
+}
+
+/// Makes an image out of native endian bytes of the colour type given
+fn image_from_bytes(width: u32, height: u32, color: ColorType, bytes: Vec<u8>) -> Option<DynamicImage> {
+    // The bytes might not line up for u16s, so those get copied out
+    let wide = || bytemuck::pod_collect_to_vec::<u8, u16>(&bytes);
+    Some(match color {
+        ColorType::L8 => DynamicImage::ImageLuma8(ImageBuffer::from_raw(width, height, bytes)?),
+        ColorType::La8 => DynamicImage::ImageLumaA8(ImageBuffer::from_raw(width, height, bytes)?),
+        ColorType::Rgb8 => DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, bytes)?),
+        ColorType::Rgba8 => DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, bytes)?),
+        ColorType::L16 => DynamicImage::ImageLuma16(ImageBuffer::from_raw(width, height, wide())?),
+        ColorType::La16 => DynamicImage::ImageLumaA16(ImageBuffer::from_raw(width, height, wide())?),
+        ColorType::Rgb16 => DynamicImage::ImageRgb16(ImageBuffer::from_raw(width, height, wide())?),
+        ColorType::Rgba16 => DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, wide())?),
+        _ => return None
+    })
+}
+
+/// The size and colour type of the image at path, read from its header without decoding the pixels.
+/// Only PNGs and TIFFs say what colour type they are this way, everything else is guessed from the format
+pub fn header(path: &str) -> Result<((u32, u32), ColorType), ImageDataErrors> {
+    if let Some(reader) = StripReader::open(path)? {
+        return Ok((reader.dimensions(), reader.color()));
+    }
+
+    let reader = open_image(path)?;
+    let format = reader.format().ok_or_else(|| ImageDataErrors::UnableToFormatImage(path.to_string()))?;
+    let dimensions = reader.into_dimensions().map_err(|e| ImageDataErrors::UnableToDecodeImage(path.to_string(), e))?;
+
+    let color = match format {
+        ImageFormat::OpenExr | ImageFormat::Hdr => ColorType::Rgba32F,
+        _ => ColorType::Rgba8
+    };
+    Ok((dimensions, color))
+}
+
+/// Roughly the most memory combining images with these headers all at once takes: every image decoded,
+/// a resized rgba copy of each, the combined image and the copy of it made while encoding
+pub fn memory_needed(headers: &[((u32, u32), ColorType)], target: Target) -> u64 {
+    let dimensions: Vec<(u32, u32)> = headers.iter().map(|(dimensions, _)| *dimensions).collect();
+    let (width, height) = target_dimensions(&dimensions, target);
+    let precision = headers.iter().map(|(_, color)| Precision::of_color(*color)).max().unwrap_or(Precision::U8);
+
+    let decoded: u64 = headers
+        .iter()
+        .map(|((width, height), color)| *width as u64 * *height as u64 * color.bytes_per_pixel() as u64)
+        .sum();
+    let working = (headers.len() as u64 + 2) * width as u64 * height as u64 * 4 * precision.sample_size();
+    decoded + working
+}
+
+/// Everything about where and how the strips get saved
+pub struct StripOutput<'a> {
+    pub path: &'a str,
+    pub format: ImageFormat,
+    pub encoder: &'a EncoderOptions,
+    /// Whether the combiner can make see through pixels out of opaque ones, as there's no looking
+    /// at the whole output to find out
+    pub adds_alpha: bool
+}
+
+/// Combines the images a strip of rows at a time and saves each strip as soon as it's done,
+/// so only a few rows of each image are in memory at once. The strips are as tall as fits in the budget.
+/// The images all have to be the same size already, fit the readers that aren't
+pub fn combine_in_strips(readers: Vec<StripReader>, combiner: &dyn Combiner, output: StripOutput, budget: u64) -> Result<(u32, u32), ImageDataErrors> {
+    let (width, height) = readers[0].dimensions();
+    let colors: Vec<ColorType> = readers.iter().map(StripReader::color).collect();
+    let precision = colors.iter().map(|color| Precision::of_color(*color)).max().unwrap_or(Precision::U8);
+    let color = colors.iter().any(|color| color.has_color());
+    let alpha = output.adds_alpha || colors.iter().any(|color| color.has_alpha());
+
+    // Each row decoded, as an rgba copy, combined, converted and narrowed for saving
+    let row_bytes: u64 = readers.iter().map(StripReader::row_size).sum::<u64>()
+        + (readers.len() as u64 + 3) * width as u64 * 4 * precision.sample_size();
+    let buffers: u64 = readers.iter().map(StripReader::buffer_size).sum();
+    let rows = (budget.saturating_sub(buffers) / row_bytes.max(1)).min(height as u64) as u32;
+    if rows == 0 {
+        let needed = buffers + row_bytes;
+        return Err(ImageDataErrors::OverMemoryBudget(needed, budget, "not even one row of the images fits in it".to_string()));
+    }
+
+    let strips = Strips { readers, combiner, precision, dimensions: (width, height), rows, top: 0 };
+    let channels = Channels::new(color, alpha);
+    match output::output_precision(output.format, precision) {
+        Precision::U8 => strips.save::<u8>(&output, channels),
+        Precision::U16 => strips.save::<u16>(&output, channels),
+        Precision::F32 => strips.save::<f32>(&output, channels)
+    }?;
+    Ok((width, height))
+}
+
+/// Where combining in strips is up to
+struct Strips<'a> {
+    readers: Vec<StripReader>,
+    combiner: &'a dyn Combiner,
+    precision: Precision,
+    dimensions: (u32, u32),
+    rows: u32,
+    top: u32
+}
+
+impl Strips<'_> {
+    /// Combines and saves every strip, with samples of type T
+    fn save<T: Sample>(mut self, output: &StripOutput, channels: Channels) -> Result<(), ImageDataErrors> {
+        let (dimensions, rows) = (self.dimensions, self.rows);
+        output::save_strips::<T, _>(output.path, output.format, dimensions, channels, rows, output.encoder, || self.next::<T>())
+    }
+
+    /// Decodes the next strip of every image and combines them
+    fn next<T: Sample>(&mut self) -> Result<Vec<T>, ImageDataErrors> {
+        let count = self.rows.min(self.dimensions.1 - self.top);
+
+        // Each image's strip is decoded on its own thread, gone through in order so the error reported is always the first input's
+        let decoded: Vec<_> = self.readers.par_iter_mut().map(|reader| reader.read(count)).collect();
+        let strips = decoded.into_iter().collect::<Result<Vec<_>, _>>()?;
+
+        let combined = self.combiner.combine_strip(&strips, self.precision, self.top, self.dimensions)?;
+        self.top += count;
+        Ok(combined.samples::<T>().into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pattern::Pattern;
+    use crate::Combine;
+    use image::{GrayImage, Luma, Rgba32FImage};
+
+    /// Path in the temp folder for a file only this test uses
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("image-combiner-{}-{}", std::process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    /// Three images with every channel different, at the precision given. Floating point ones can't be
+    /// resized a strip at a time so they're all the same size, the rest have one that needs fitting
+    fn inputs(precision: Precision) -> Vec<DynamicImage> {
+        let sizes = match precision {
+            Precision::F32 => [(37, 23), (37, 23), (37, 23)],
+            _ => [(37, 23), (37, 23), (45, 31)]
+        };
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(index, (width, height))| {
+                let image = DynamicImage::ImageRgba32F(Rgba32FImage::from_fn(*width, *height, |x, y| {
+                    let value = |channel: u32| ((x * 7 + y * 13 + channel * 29 + index as u32 * 31) % 64) as f32 / 63.0;
+                    Rgba([value(0), value(1), value(2), 0.5 + value(3) / 2.0])
+                }));
+                match precision {
+                    Precision::U8 => DynamicImage::ImageRgba8(image.to_rgba8()),
+                    Precision::U16 => DynamicImage::ImageRgba16(image.to_rgba16()),
+                    Precision::F32 => image
+                }
+            })
+            .collect()
+    }
+
+    /// The colour type and samples of the image at path. The image crate can't read floating point TIFFs,
+    /// so those get read by the tiff crate instead
+    fn decode(path: &str) -> (String, Vec<u8>) {
+        if let Ok(mut decoder) = Decoder::new(BufReader::new(File::open(path).unwrap())) {
+            if let DecodingResult::F32(samples) = decoder.read_image().unwrap() {
+                return (format!("{:?}", decoder.colortype().unwrap()), bytemuck::cast_slice(&samples).to_vec());
+            }
+        }
+        let image = image::open(path).unwrap();
+        (format!("{:?}", image.color()), image.into_bytes())
+    }
+
+    /// Saves the images combined whole and a strip at a time, and checks both files decode to the same pixels
+    fn assert_strips_match(name: &str, combine: Combine, format: &str) {
+        let whole = temp_path(&format!("{}-whole.{}", name, format));
+        let strips = temp_path(&format!("{}-strips.{}", name, format));
+
+        assert!(!combine.clone().save(&whole).unwrap().in_strips);
+        // Only a few rows of each image fit, so there are a handful of strips and the last one is shorter
+        assert!(combine.memory_budget(16 * 1024).save(&strips).unwrap().in_strips, "{} wasn't combined in strips", name);
+
+        let (expected, actual) = (decode(&whole), decode(&strips));
+        let _ = (std::fs::remove_file(&whole), std::fs::remove_file(&strips));
+        assert_eq!(expected.0, actual.0, "{}", name);
+        assert!(expected.1 == actual.1, "{} came out different a strip at a time", name);
+    }
+
+    #[test]
+    fn strips_match_combining_whole() {
+        let mut patterns: Vec<(&str, Pattern)> = ["pixels", "rows:3", "columns:2", "checkerboard:3x2", "tiles:3x2", "random:7", "bayer:4", "blue-noise:7"]
+            .into_iter()
+            .map(|pattern| (pattern, pattern.parse().unwrap()))
+            .collect();
+        patterns.push(("bitmap", Pattern::Bitmap(GrayImage::from_fn(5, 4, |x, y| Luma([(x * 50 + y * 10) as u8])))));
+
+        for precision in [Precision::U8, Precision::U16, Precision::F32] {
+            let formats: &[&str] = if precision == Precision::F32 { &["tif"] } else { &["png", "tif"] };
+            for format in formats {
+                let combine = inputs(precision).into_iter().fold(Combine::new(), Combine::image);
+                for (name, pattern) in &patterns {
+                    let name = format!("{:?}-{}", precision, name.replace(':', "-"));
+                    assert_strips_match(&name, combine.clone().pattern(pattern.clone()), format);
+                }
+                for mode in ["multiply", "hue", "xor"] {
+                    assert_strips_match(&format!("{:?}-{}", precision, mode), combine.clone().mode(mode), format);
+                }
+                // Every way of fitting the odd one out, and the filters that resize it differently
+                for fit in ["crop", "pad:#ff000080", "letterbox", "stretch"] {
+                    for (filter, linear) in [("lanczos3", false), ("pixel-art", false), ("triangle", true)] {
+                        let policy = SizePolicy {
+                            fit: fit.parse().unwrap(),
+                            resampling: Resampling { filter: filter.parse().unwrap(), linear },
+                            ..SizePolicy::default()
+                        };
+                        let name = format!("{:?}-{}-{}-{}", precision, fit.replace([':', '#'], "-"), filter, if linear { "linear" } else { "srgb" });
+                        assert_strips_match(&name, combine.clone().size_policy(policy), format);
+                    }
+                }
+            }
+        }
+    }
+}